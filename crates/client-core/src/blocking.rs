@@ -0,0 +1,92 @@
+use crate::Client;
+pub use crate::Error;
+use common::codec::Codec;
+use common::fragment::{Fragment, MAX_DATAGRAM_SIZE};
+use common::retry::RetryPolicy;
+use common::{ErrorKind, Instruction, Response};
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How long to block on the socket before checking for timed out requests.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Session with the server that sends one request at a time and blocks until
+/// its response arrives, for frontends without an event loop.
+pub struct Connection {
+    client: Client<()>,
+}
+
+impl Connection {
+    /// Binds a socket to `bind`, and completes the handshake with `server`.
+    /// Requests are resent according to `policy`.
+    pub fn open(
+        bind: &str,
+        server: &str,
+        policy: RetryPolicy,
+        client_name: &str,
+        token: Option<String>,
+    ) -> Result<Self, Error> {
+        let mut client = Client::connect(bind, server, policy)?;
+        client.socket().set_read_timeout(Some(POLL_INTERVAL))?;
+        client.hello(client_name, token, ())?;
+        let mut connection = Self { client };
+        let response = connection.wait()?;
+        match crate::handshake(Ok(response)) {
+            Ok(codec) => {
+                connection.client.set_codec(codec);
+                Ok(connection)
+            }
+            Err(message) => Err(Error::Fail {
+                kind: ErrorKind::ProtocolVersionMismatch,
+                message,
+            }),
+        }
+    }
+
+    /// Sends `instruction` and waits for its response. [`Response::Fail`] is
+    /// turned into [`Error::Fail`].
+    pub fn request(&mut self, instruction: Instruction) -> Result<Response, Error> {
+        self.client.send(instruction, ())?;
+        self.wait()
+    }
+
+    /// Waits for the response to the only request in flight.
+    fn wait(&mut self) -> Result<Response, Error> {
+        let socket = self.client.socket();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        loop {
+            match socket.recv(&mut buf) {
+                // Fragments of responses to earlier requests may still
+                // arrive, they are of no use anymore.
+                Ok(bytes) => match Codec::decode::<Fragment>(&buf[..bytes]) {
+                    Ok(fragment) if self.client.is_pending(fragment.id) => {
+                        if let Some(((), result)) = self.client.receive(fragment).pop() {
+                            return result;
+                        }
+                    }
+                    _ => {}
+                },
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(((), error)) = self.client.poll(Instant::now()).pop() {
+                return Err(error);
+            }
+        }
+    }
+}
+
+/// Error for a response of the wrong kind.
+pub fn unexpected(response: Response) -> Error {
+    match response {
+        Response::Fail { kind, message } => Error::Fail { kind, message },
+        response => Error::Fail {
+            kind: ErrorKind::ProtocolVersionMismatch,
+            message: format!("Unexpected response from server: {:?}", response),
+        },
+    }
+}
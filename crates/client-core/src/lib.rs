@@ -0,0 +1,495 @@
+//! Request and response handling shared by the clients of the phone number
+//! server, so every frontend talks to it the same way. Frontends with an event
+//! loop keep a [`session::Session`] and only render it, the others send one
+//! request at a time through a [`blocking::Connection`].
+
+pub mod blocking;
+pub mod session;
+
+use common::codec::Codec;
+use common::fragment::{Fragment, Reassembler};
+use common::retry::{Outstanding, RetryPolicy};
+use common::{Envelope, ErrorKind, Instruction, RequestId, Response, PROTOCOL_VERSION};
+use log::{debug, warn};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Number of contacts requested per page.
+pub const PAGE_SIZE: u32 = 50;
+
+/// Time the search query has to stay unchanged before it is sent.
+pub const SEARCH_DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Number of search results requested.
+pub const SEARCH_LIMIT: u32 = 20;
+
+/// Why a request failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The server refused the request.
+    Fail {
+        kind: ErrorKind,
+        message: String,
+    },
+    /// No response arrived, even after every retransmission, or nothing
+    /// listens on the server's port.
+    Unreachable(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fail { message, .. } => write!(f, "{}", message),
+            Self::Unreachable(message) => write!(f, "{}", message),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Copy of the error, for failing several requests with it.
+    fn duplicate(&self) -> Self {
+        match self {
+            Self::Fail { kind, message } => Self::Fail {
+                kind: *kind,
+                message: message.clone(),
+            },
+            Self::Unreachable(message) => Self::Unreachable(message.clone()),
+            Self::Io(e) => Self::Io(io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // Reported for connected sockets when nothing listens on the
+            // server's port.
+            io::ErrorKind::ConnectionRefused => {
+                Self::Unreachable(format!("Server unreachable: {}", e))
+            }
+            _ => Self::Io(e),
+        }
+    }
+}
+
+/// [`Response::Fail`] as an [`Error`], other responses as they are.
+fn result(response: Response) -> Result<Response, Error> {
+    match response {
+        Response::Fail { kind, message } => Err(Error::Fail { kind, message }),
+        response => Ok(response),
+    }
+}
+
+/// What a request in flight was sent for.
+enum Request<T> {
+    /// Sent by the frontend. The instruction is kept to repeat the request
+    /// if the server lost the session, which happens at most once.
+    Frontend {
+        tag: T,
+        instruction: Instruction,
+        repeated: bool,
+    },
+    /// Handshake redone because the server lost the session.
+    Rehello,
+}
+
+/// Connection to the server that keeps track of the requests in flight.
+///
+/// Requests are sent right away and answered later: fragments received on
+/// [`Client::socket`] are handed to [`Client::receive`], which returns the
+/// response once it is complete, and [`Client::poll`] has to be called
+/// regularly to resend requests that timed out. `T` is whatever the frontend
+/// needs to handle a response, it is returned together with it.
+///
+/// When the server restarted or evicted the session in the meantime, the
+/// client redoes the handshake and repeats the request on its own.
+///
+/// Diagnostics are reported through the `log` crate, frontends drawing on
+/// the terminal simply do not install a logger.
+pub struct Client<T> {
+    socket: Arc<UdpSocket>,
+    /// Codec requests are encoded with, negotiated in the handshake.
+    codec: Codec,
+    next_id: RequestId,
+    pending: Outstanding<Request<T>>,
+    reassembler: Reassembler,
+    /// Handshake sent by the frontend, to redo it when the session is lost.
+    hello: Option<Instruction>,
+    /// Requests to repeat once the handshake is redone, the handshake is in
+    /// flight as long as this is not empty.
+    waiting: Vec<(T, Instruction)>,
+}
+
+impl<T> Client<T> {
+    /// Binds a socket to `bind` and connects it to `server`, nothing is sent
+    /// before [`Client::hello`]. Requests are resent according to `policy`.
+    pub fn connect(bind: &str, server: &str, policy: RetryPolicy) -> io::Result<Self> {
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(server)?;
+        Ok(Self {
+            socket: Arc::new(socket),
+            codec: Codec::Json,
+            // Seed ids from the clock so requests of a restarted client are
+            // not mistaken for the old ones.
+            next_id: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|x| x.as_nanos() as RequestId)
+                .unwrap_or(0),
+            pending: Outstanding::new(policy),
+            reassembler: Reassembler::new(),
+            hello: None,
+            waiting: Vec::new(),
+        })
+    }
+
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        self.socket.clone()
+    }
+
+    /// Starts the handshake, its response has to be passed to [`handshake`].
+    pub fn hello(&mut self, client_name: &str, token: Option<String>, tag: T) -> io::Result<()> {
+        let instruction = Instruction::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            client_name: client_name.to_owned(),
+            codecs: vec![Codec::Binary, Codec::Json],
+            token,
+        };
+        self.hello = Some(instruction.clone());
+        self.send(instruction, tag).map(|_| ())
+    }
+
+    /// Encodes further requests with `codec`, as agreed on in the handshake.
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    /// Sends `instruction`, its response is returned with `tag` once it arrives.
+    pub fn send(&mut self, instruction: Instruction, tag: T) -> io::Result<RequestId> {
+        let (id, bytes) = self.transmit(&instruction)?;
+        let request = Request::Frontend {
+            tag,
+            instruction,
+            repeated: false,
+        };
+        self.pending.insert(id, bytes, request);
+        Ok(id)
+    }
+
+    /// Sends `instruction` under a fresh id, returns the id and the datagram.
+    fn transmit(&mut self, instruction: &Instruction) -> io::Result<(RequestId, Vec<u8>)> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let bytes = self
+            .codec
+            .encode(&Envelope::new(self.next_id, instruction))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.socket.send(&bytes)?;
+        Ok((self.next_id, bytes))
+    }
+
+    pub fn is_pending(&self, id: RequestId) -> bool {
+        self.pending.contains(id)
+    }
+
+    /// Stores `fragment`, returns the responses it completes together with the
+    /// tags of their requests. [`Response::Fail`] is returned as
+    /// [`Error::Fail`].
+    ///
+    /// Usually a fragment completes at most one response, but all requests
+    /// waiting for a redone handshake fail together when it is refused.
+    pub fn receive(&mut self, fragment: Fragment) -> Vec<(T, Result<Response, Error>)> {
+        if !self.pending.contains(fragment.id) {
+            warn!("Dropping fragment of unknown request {}", fragment.id);
+            return Vec::new();
+        }
+        let bytes = match self.reassembler.push(fragment) {
+            Some(bytes) => bytes,
+            None => return Vec::new(),
+        };
+        match Codec::decode::<Envelope<Response>>(&bytes) {
+            Ok(Envelope { id, body }) => match self.pending.remove(id) {
+                Some(request) => self.complete(request, result(body)),
+                None => Vec::new(),
+            },
+            Err(e) => {
+                warn!("Dropping malformed response: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Hands the outcome of `request` to the frontend, unless the server
+    /// lost the session and the request has to wait for a new handshake.
+    fn complete(
+        &mut self,
+        request: Request<T>,
+        result: Result<Response, Error>,
+    ) -> Vec<(T, Result<Response, Error>)> {
+        match (request, result) {
+            (
+                Request::Frontend {
+                    tag,
+                    instruction,
+                    repeated: false,
+                },
+                Err(Error::Fail {
+                    kind: ErrorKind::HandshakeRequired,
+                    message,
+                }),
+            ) => {
+                let hello = match &self.hello {
+                    Some(hello) if self.waiting.is_empty() => hello.clone(),
+                    Some(_) => {
+                        self.waiting.push((tag, instruction));
+                        return Vec::new();
+                    }
+                    None => {
+                        let error = Error::Fail {
+                            kind: ErrorKind::HandshakeRequired,
+                            message,
+                        };
+                        return vec![(tag, Err(error))];
+                    }
+                };
+                debug!("Server lost the session, redoing the handshake");
+                match self.transmit(&hello) {
+                    Ok((id, bytes)) => {
+                        self.pending.insert(id, bytes, Request::Rehello);
+                        self.waiting.push((tag, instruction));
+                        Vec::new()
+                    }
+                    Err(e) => vec![(tag, Err(e.into()))],
+                }
+            }
+            (Request::Frontend { tag, .. }, result) => vec![(tag, result)],
+            (Request::Rehello, result) => {
+                let codec = result.and_then(|response| {
+                    handshake(Ok(response)).map_err(|message| Error::Fail {
+                        kind: ErrorKind::ProtocolVersionMismatch,
+                        message,
+                    })
+                });
+                match codec {
+                    Ok(codec) => {
+                        self.codec = codec;
+                        let mut failed = Vec::new();
+                        for (tag, instruction) in mem::take(&mut self.waiting) {
+                            match self.transmit(&instruction) {
+                                Ok((id, bytes)) => {
+                                    let request = Request::Frontend {
+                                        tag,
+                                        instruction,
+                                        repeated: true,
+                                    };
+                                    self.pending.insert(id, bytes, request);
+                                }
+                                Err(e) => failed.push((tag, Err(e.into()))),
+                            }
+                        }
+                        failed
+                    }
+                    Err(e) => self
+                        .fail_waiting(&e)
+                        .into_iter()
+                        .map(|(tag, e)| (tag, Err(e)))
+                        .collect(),
+                }
+            }
+        }
+    }
+
+    /// Fails the requests waiting for the redone handshake with `error`.
+    fn fail_waiting(&mut self, error: &Error) -> Vec<(T, Error)> {
+        mem::take(&mut self.waiting)
+            .into_iter()
+            .map(|(tag, _)| (tag, error.duplicate()))
+            .collect()
+    }
+
+    /// Resends the requests that timed out at `now`. Requests that ran out of
+    /// retransmissions are given up on, they are returned with
+    /// [`Error::Unreachable`].
+    pub fn poll(&mut self, now: Instant) -> Vec<(T, Error)> {
+        let Self {
+            socket,
+            codec,
+            next_id,
+            pending,
+            reassembler,
+            ..
+        } = self;
+        let expired = pending.poll(now, |id, datagram| {
+            // Only ask for the missing part of a partially received response.
+            let datagram = match reassembler.missing(id) {
+                Some(indices) => {
+                    debug!("Response {} is incomplete, requesting {:?}", id, indices);
+                    *next_id = next_id.wrapping_add(1);
+                    let instruction = Instruction::ResendFragments {
+                        request: id,
+                        indices,
+                    };
+                    match codec.encode(&Envelope::new(*next_id, instruction)) {
+                        Ok(bytes) => bytes,
+                        Err(_) => datagram.to_vec(),
+                    }
+                }
+                None => {
+                    debug!("Request {} timed out, resending", id);
+                    datagram.to_vec()
+                }
+            };
+            if let Err(e) = socket.send(&datagram) {
+                warn!("Failed to resend request {}: {}", id, e);
+            }
+        });
+        let mut failed = Vec::new();
+        for (id, request, error) in expired {
+            self.reassembler.remove(id);
+            let error = Error::Unreachable(error.to_string());
+            match request {
+                Request::Frontend { tag, .. } => failed.push((tag, error)),
+                Request::Rehello => failed.extend(self.fail_waiting(&error)),
+            }
+        }
+        failed
+    }
+}
+
+/// Codec to use from now on if the server accepted the handshake, or why it
+/// did not.
+pub fn handshake(result: Result<Response, Error>) -> Result<Codec, String> {
+    match result {
+        Ok(Response::Hello {
+            versions, codec, ..
+        }) if versions.contains(&PROTOCOL_VERSION) => Ok(codec),
+        Ok(Response::Hello { versions, .. }) => Err(format!(
+            "server supports protocol versions {:?}, this client speaks version {}",
+            versions, PROTOCOL_VERSION
+        )),
+        Ok(_) => Err("unexpected response from server".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Whether `number` is a valid international phone number.
+pub fn is_valid_number(number: &str) -> bool {
+    phonenumber::parse(None, number)
+        .map(|x| phonenumber::is_valid(&x))
+        .unwrap_or(false)
+}
+
+/// Text shown for a failed request, worded after what the user can do about it.
+pub fn describe(error: &Error) -> String {
+    let (kind, message) = match error {
+        Error::Fail { kind, message } => (*kind, message),
+        Error::Unreachable(message) => return message.clone(),
+        Error::Io(e) => return format!("Failed to talk to the server: {}", e),
+    };
+    match kind {
+        // The server names the contact, number, group or field concerned.
+        ErrorKind::NotFound | ErrorKind::AlreadyExists | ErrorKind::InvalidField => {
+            message.to_owned()
+        }
+        ErrorKind::InvalidNumber => "Server rejected the phone number".to_owned(),
+        ErrorKind::StorageError => format!("Server failed to store the change: {}", message),
+        ErrorKind::Unauthorized => "Not authorized to use this server".to_owned(),
+        ErrorKind::RateLimited => "Too many requests, try again in a moment".to_owned(),
+        ErrorKind::ProtocolVersionMismatch => {
+            "Client is not compatible with the server, please update it".to_owned()
+        }
+        ErrorKind::HandshakeRequired => "Server has lost the session, please reconnect".to_owned(),
+    }
+}
+
+/// Whether a failure concerns the connection rather than a single request,
+/// frontends show those prominently.
+pub fn is_connection_error(error: &Error) -> bool {
+    match error {
+        Error::Fail { kind, .. } => matches!(
+            kind,
+            ErrorKind::Unauthorized
+                | ErrorKind::RateLimited
+                | ErrorKind::ProtocolVersionMismatch
+                | ErrorKind::HandshakeRequired
+        ),
+        Error::Unreachable(_) | Error::Io(_) => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::fragment::{self, MAX_DATAGRAM_SIZE};
+
+    /// Receives a request on `server` and answers it with `response`.
+    fn answer(server: &UdpSocket, response: Response) -> (RequestId, Instruction) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let (bytes, addr) = server.recv_from(&mut buf).unwrap();
+        let Envelope { id, body } = Codec::decode::<Envelope<Instruction>>(&buf[..bytes]).unwrap();
+        let bytes = Codec::Json.encode(&Envelope::new(id, response)).unwrap();
+        for fragment in fragment::split(id, &bytes) {
+            let datagram = Codec::Json.encode(&fragment).unwrap();
+            server.send_to(&datagram, addr).unwrap();
+        }
+        (id, body)
+    }
+
+    /// Passes the next fragment arriving on the socket of `client` to it, the
+    /// responses of the test fit into one fragment each.
+    fn receive(client: &mut Client<&'static str>) -> Vec<(&'static str, Result<Response, Error>)> {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let bytes = client.socket().recv(&mut buf).unwrap();
+        let fragment = Codec::decode::<Fragment>(&buf[..bytes]).unwrap();
+        client.receive(fragment)
+    }
+
+    #[test]
+    fn lost_session_is_restored() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let mut client = Client::connect("127.0.0.1:0", &address, RetryPolicy::default()).unwrap();
+        client
+            .socket()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let hello = Response::Hello {
+            versions: vec![PROTOCOL_VERSION],
+            capabilities: vec![],
+            codec: Codec::Json,
+        };
+
+        client.hello("test", None, "hello").unwrap();
+        answer(&server, hello.clone());
+        assert!(matches!(receive(&mut client)[..], [("hello", Ok(_))]));
+
+        // The server restarted in the meantime.
+        client.send(Instruction::ListGroups, "groups").unwrap();
+        let (first, _) = answer(
+            &server,
+            Response::Fail {
+                kind: ErrorKind::HandshakeRequired,
+                message: "Handshake required, send Hello first".to_owned(),
+            },
+        );
+        assert!(receive(&mut client).is_empty());
+
+        let (_, instruction) = answer(&server, hello);
+        assert!(matches!(instruction, Instruction::Hello { .. }));
+        assert!(receive(&mut client).is_empty());
+        let (repeated, instruction) = answer(&server, Response::Groups(vec![]));
+        assert!(matches!(instruction, Instruction::ListGroups));
+        // The server remembers the failed response under the old id.
+        assert_ne!(first, repeated);
+        assert!(matches!(
+            receive(&mut client)[..],
+            [("groups", Ok(Response::Groups(_)))]
+        ));
+    }
+}
@@ -0,0 +1,703 @@
+use crate::{
+    describe, handshake, is_connection_error, Client, Error, PAGE_SIZE, SEARCH_DEBOUNCE,
+    SEARCH_LIMIT,
+};
+use common::fragment::Fragment;
+use common::retry::RetryPolicy;
+use common::{ContactInfo, ErrorKind, Instruction, LabelledNumber, Response};
+use std::io;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Label of the number a contact is added with.
+const DEFAULT_LABEL: &str = "mobile";
+
+pub enum Handshake {
+    Pending,
+    Done,
+    Refused(String),
+}
+
+/// Whether the server has confirmed the last change made to a contact.
+#[derive(Debug, Clone)]
+pub enum Status {
+    Pending,
+    Confirmed,
+    Failed(String),
+}
+
+/// Contact as far as the client knows it, with the outcome of its last change.
+pub struct Contact {
+    pub info: ContactInfo,
+    pub status: Status,
+}
+
+/// What a request in flight is about, so its response can be applied once it
+/// arrives.
+enum Pending {
+    Hello,
+    Add {
+        name: String,
+    },
+    /// New primary `number` of contact `name`.
+    Edit {
+        name: String,
+        number: String,
+    },
+    /// Change answered with the updated contact, e.g. of its details or of
+    /// the numbers besides the primary one.
+    Contact {
+        name: String,
+    },
+    Delete {
+        name: String,
+    },
+    Lookup {
+        key: String,
+    },
+    Search {
+        query: String,
+    },
+    FuzzySearch {
+        query: String,
+    },
+    Groups,
+    CreateGroup,
+    RenameGroup {
+        name: String,
+        new_name: String,
+    },
+    /// `first` pages replace the contact list, others extend it. Pages of an
+    /// older `listing` are dropped.
+    Page {
+        first: bool,
+        listing: u64,
+    },
+}
+
+/// Everything a frontend shows about the server: the contacts loaded page by
+/// page, the search results and the groups, kept up to date as responses
+/// arrive.
+///
+/// Frontends forward the fragments received on [`Session::socket`] to
+/// [`Session::receive`], call [`Session::tick`] regularly and render what the
+/// getters return. Changes are applied to the contact list right away and
+/// marked [`Status::Pending`] until the server confirms them.
+pub struct Session {
+    client: Client<Pending>,
+    handshake: Handshake,
+    /// Loaded contacts, ordered by name like the server hands them out.
+    contacts: Vec<Contact>,
+    /// Where the next page of contacts starts, `None` once all are loaded.
+    next_cursor: Option<String>,
+    loading_page: bool,
+    /// Counts the first pages requested, so pages of a previous listing that
+    /// arrive late are recognized, e.g. after the group filter changed.
+    listing: u64,
+    /// Group the contact list is limited to.
+    group_filter: Option<String>,
+    groups: Vec<String>,
+    search: String,
+    /// When the search query was last edited, `None` once it was sent.
+    search_edited: Option<Instant>,
+    search_results: Vec<ContactInfo>,
+    /// Whether `search_results` are only similar names, shown when nothing
+    /// matched the query exactly.
+    search_fuzzy: bool,
+    lookup: String,
+    /// Outcome of the last change.
+    status: String,
+    err: String,
+}
+
+impl Session {
+    /// Binds a socket to `bind`, connects it to `server` and starts the
+    /// handshake. The first page of contacts and the groups are requested
+    /// once the server accepted it. Requests are resent according to
+    /// `policy`.
+    pub fn connect(
+        bind: &str,
+        server: &str,
+        policy: RetryPolicy,
+        client_name: &str,
+        token: Option<String>,
+    ) -> io::Result<Self> {
+        let mut client = Client::connect(bind, server, policy)?;
+        client.hello(client_name, token, Pending::Hello)?;
+        Ok(Self {
+            client,
+            handshake: Handshake::Pending,
+            contacts: vec![],
+            next_cursor: None,
+            loading_page: false,
+            listing: 0,
+            group_filter: None,
+            groups: vec![],
+            search: String::new(),
+            search_edited: None,
+            search_results: vec![],
+            search_fuzzy: false,
+            lookup: String::new(),
+            status: String::new(),
+            err: String::new(),
+        })
+    }
+
+    pub fn socket(&self) -> Arc<UdpSocket> {
+        self.client.socket()
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    pub fn contacts(&self) -> &[Contact] {
+        &self.contacts
+    }
+
+    pub fn contact(&self, name: &str) -> Option<&Contact> {
+        self.contacts.iter().find(|x| x.info.name == name)
+    }
+
+    /// Whether more contacts than the loaded ones exist.
+    pub fn has_more(&self) -> bool {
+        self.next_cursor.is_some()
+    }
+
+    pub fn loading_page(&self) -> bool {
+        self.loading_page
+    }
+
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    pub fn group_filter(&self) -> Option<&str> {
+        self.group_filter.as_deref()
+    }
+
+    pub fn search(&self) -> &str {
+        &self.search
+    }
+
+    pub fn search_results(&self) -> &[ContactInfo] {
+        &self.search_results
+    }
+
+    pub fn search_fuzzy(&self) -> bool {
+        self.search_fuzzy
+    }
+
+    /// Outcome of the last [`Session::lookup`].
+    pub fn lookup_result(&self) -> &str {
+        &self.lookup
+    }
+
+    /// Outcome of the last change of a contact.
+    pub fn status(&self) -> &str {
+        &self.status
+    }
+
+    /// Last failure that does not concern a single contact, e.g. of the
+    /// connection or of a search.
+    pub fn error(&self) -> &str {
+        &self.err
+    }
+
+    pub fn clear_error(&mut self) {
+        self.err.clear();
+    }
+
+    /// Sends `instruction` to the server, the response is handed to
+    /// [`Session::apply`] together with `pending` once it arrives.
+    fn send(&mut self, instruction: Instruction, pending: Pending) {
+        if let Err(e) = self.client.send(instruction, pending) {
+            self.err = format!("Failed to send request: {}", e);
+        }
+    }
+
+    /// Handles a fragment received on [`Session::socket`].
+    pub fn receive(&mut self, fragment: Fragment) {
+        for (pending, result) in self.client.receive(fragment) {
+            self.apply(pending, result);
+        }
+    }
+
+    /// Sends the search query once the user stopped typing, and resends the
+    /// requests that timed out.
+    pub fn tick(&mut self, now: Instant) {
+        match self.search_edited {
+            Some(edited) if now.duration_since(edited) >= SEARCH_DEBOUNCE => {
+                self.search_edited = None;
+                let query = self.search.clone();
+                self.send(
+                    Instruction::Search {
+                        query: query.clone(),
+                        fields: vec![],
+                        limit: SEARCH_LIMIT,
+                    },
+                    Pending::Search { query },
+                );
+            }
+            _ => {}
+        }
+        for (pending, error) in self.client.poll(now) {
+            self.apply(pending, Err(error));
+        }
+    }
+
+    /// Requests the first or the next page of contacts in the selected group.
+    pub fn fetch_page(&mut self, first: bool) {
+        self.loading_page = true;
+        let cursor = if first {
+            self.listing += 1;
+            None
+        } else {
+            self.next_cursor.clone()
+        };
+        self.send(
+            Instruction::ListUsers {
+                cursor,
+                limit: PAGE_SIZE,
+                group: self.group_filter.clone(),
+            },
+            Pending::Page {
+                first,
+                listing: self.listing,
+            },
+        );
+    }
+
+    /// Requests the next page, unless all contacts are loaded or a page is
+    /// on its way already.
+    pub fn load_more(&mut self) {
+        if self.next_cursor.is_some() && !self.loading_page {
+            self.fetch_page(false);
+        }
+    }
+
+    /// Limits the contact list to `group`, `None` shows all contacts.
+    pub fn filter_group(&mut self, group: Option<String>) {
+        self.group_filter = group;
+        self.fetch_page(true);
+    }
+
+    /// Changes the search query, it is sent once it stayed the same for
+    /// [`SEARCH_DEBOUNCE`]. An empty query ends the search.
+    pub fn set_search(&mut self, query: String, now: Instant) {
+        self.search_edited = if query.trim().is_empty() {
+            self.search_results.clear();
+            self.search_fuzzy = false;
+            None
+        } else {
+            Some(now)
+        };
+        self.search = query;
+    }
+
+    /// Adds a contact, or replaces the primary number of a known one.
+    pub fn add(&mut self, name: String, number: String) {
+        self.status = "Saving...".to_owned();
+        if let Some(contact) = self.contact_mut(&name) {
+            contact.status = Status::Pending;
+            self.send(
+                Instruction::UpsertNumber {
+                    key: name.clone(),
+                    number: number.clone(),
+                },
+                Pending::Edit { name, number },
+            );
+            return;
+        }
+        self.send(
+            Instruction::AddPhoneNumber {
+                key: name.clone(),
+                number: number.clone(),
+            },
+            Pending::Add { name: name.clone() },
+        );
+        let info = ContactInfo {
+            name,
+            numbers: vec![LabelledNumber {
+                label: DEFAULT_LABEL.to_owned(),
+                number: number.clone(),
+                display: number,
+                primary: true,
+            }],
+            details: Default::default(),
+            groups: vec![],
+        };
+        let i = self.contacts.partition_point(|x| x.info.name < info.name);
+        self.contacts.insert(
+            i,
+            Contact {
+                info,
+                status: Status::Pending,
+            },
+        );
+    }
+
+    /// Changes the primary number of contact `name`.
+    pub fn edit_number(&mut self, name: String, number: String) {
+        self.status = "Saving...".to_owned();
+        if let Some(contact) = self.contact_mut(&name) {
+            contact.status = Status::Pending;
+        }
+        self.send(
+            Instruction::EditNumber {
+                key: name.clone(),
+                number: number.clone(),
+            },
+            Pending::Edit { name, number },
+        );
+    }
+
+    /// Sends a change of contact `name` the server answers with the updated
+    /// contact, e.g. `Instruction::EditDetails` or `Instruction::AddNumber`.
+    pub fn change(&mut self, name: String, instruction: Instruction) {
+        self.status = "Saving...".to_owned();
+        if let Some(contact) = self.contact_mut(&name) {
+            contact.status = Status::Pending;
+        }
+        self.send(instruction, Pending::Contact { name });
+    }
+
+    pub fn delete(&mut self, name: String) {
+        self.status = "Deleting...".to_owned();
+        if let Some(contact) = self.contact_mut(&name) {
+            contact.status = Status::Pending;
+        }
+        self.send(
+            Instruction::DeleteUser { key: name.clone() },
+            Pending::Delete { name },
+        );
+    }
+
+    /// Looks up the primary number of `key`, see [`Session::lookup_result`].
+    pub fn lookup(&mut self, key: String) {
+        self.lookup = "Looking up...".to_owned();
+        self.send(
+            Instruction::GetNumber { key: key.clone() },
+            Pending::Lookup { key },
+        );
+    }
+
+    pub fn create_group(&mut self, name: String) {
+        self.send(Instruction::CreateGroup { name }, Pending::CreateGroup);
+    }
+
+    pub fn rename_group(&mut self, name: String, new_name: String) {
+        self.send(
+            Instruction::RenameGroup {
+                name: name.clone(),
+                new_name: new_name.clone(),
+            },
+            Pending::RenameGroup { name, new_name },
+        );
+    }
+
+    fn contact_mut(&mut self, name: &str) -> Option<&mut Contact> {
+        self.contacts.iter_mut().find(|x| x.info.name == name)
+    }
+
+    /// Records the outcome of a change of contact `name`.
+    fn finish(&mut self, name: &str, result: Result<String, String>) {
+        let status = match &result {
+            Ok(_) => Status::Confirmed,
+            Err(message) => Status::Failed(message.clone()),
+        };
+        if let Some(contact) = self.contact_mut(name) {
+            contact.status = status;
+        }
+        self.status = result.unwrap_or_else(|message| message);
+    }
+
+    /// Applies the response to a request once it arrives.
+    fn apply(&mut self, pending: Pending, result: Result<Response, Error>) {
+        // Failures concerning the connection rather than a single request are
+        // reported on their own as well.
+        if let Err(e) = &result {
+            if is_connection_error(e) {
+                self.err = describe(e);
+            }
+        }
+        match pending {
+            Pending::Hello => {
+                self.handshake = match handshake(result) {
+                    Ok(codec) => {
+                        self.client.set_codec(codec);
+                        Handshake::Done
+                    }
+                    Err(reason) => Handshake::Refused(reason),
+                };
+                if let Handshake::Done = self.handshake {
+                    self.send(Instruction::ListGroups, Pending::Groups);
+                    self.fetch_page(true);
+                }
+            }
+            Pending::Add { name } => {
+                let result = outcome(result, format!("Added {}", name));
+                self.finish(&name, result);
+            }
+            Pending::Edit { name, number } => {
+                let result = outcome(result, format!("Changed the number of {}", name));
+                if result.is_ok() {
+                    if let Some(contact) = self.contact_mut(&name) {
+                        set_primary(&mut contact.info, number);
+                    }
+                }
+                self.finish(&name, result);
+            }
+            Pending::Contact { name } => {
+                let result = match result {
+                    Ok(Response::Contact(info)) => {
+                        if let Some(contact) = self.contact_mut(&name) {
+                            contact.info = info;
+                        }
+                        Ok(format!("Saved {}", name))
+                    }
+                    result => outcome(result, format!("Saved {}", name)),
+                };
+                self.finish(&name, result);
+            }
+            Pending::Delete { name } => match result {
+                // Somebody else deleted it already, the outcome is the same.
+                Ok(Response::Success)
+                | Err(Error::Fail {
+                    kind: ErrorKind::NotFound,
+                    ..
+                }) => {
+                    self.contacts.retain(|x| x.info.name != name);
+                    self.search_results.retain(|x| x.name != name);
+                    self.status = format!("Deleted {}", name);
+                }
+                result => self.finish(&name, Err(failure(result))),
+            },
+            // Results of a query the user has typed over already are dropped.
+            Pending::Search { query } if query == self.search => match result {
+                // Nothing matched exactly, look for similar names instead.
+                Ok(Response::SearchResults(contacts)) if contacts.is_empty() => {
+                    self.send(
+                        Instruction::FuzzySearch {
+                            query: query.clone(),
+                            limit: SEARCH_LIMIT,
+                        },
+                        Pending::FuzzySearch { query },
+                    );
+                }
+                Ok(Response::SearchResults(contacts)) => {
+                    self.search_results = contacts;
+                    self.search_fuzzy = false;
+                }
+                result => self.err = failure(result),
+            },
+            Pending::FuzzySearch { query } if query == self.search => match result {
+                Ok(Response::ScoredResults(contacts)) => {
+                    self.search_results = contacts.into_iter().map(|x| x.contact).collect();
+                    self.search_fuzzy = true;
+                }
+                result => self.err = failure(result),
+            },
+            Pending::Search { .. } | Pending::FuzzySearch { .. } => {}
+            Pending::Lookup { key } => {
+                self.lookup = match result {
+                    Ok(Response::Number { display, .. }) => format!("{}: {}", key, display),
+                    // Tells a missing contact apart from one without a number.
+                    result => failure(result),
+                };
+            }
+            Pending::Groups => match result {
+                Ok(Response::Groups(groups)) => self.groups = groups,
+                result => self.err = failure(result),
+            },
+            Pending::CreateGroup => match result {
+                Ok(Response::Success) => self.send(Instruction::ListGroups, Pending::Groups),
+                result => self.err = failure(result),
+            },
+            Pending::RenameGroup { name, new_name } => match result {
+                Ok(Response::Success) => {
+                    if self.group_filter.as_ref() == Some(&name) {
+                        self.group_filter = Some(new_name);
+                    }
+                    self.send(Instruction::ListGroups, Pending::Groups);
+                    // Contacts show the names of their groups.
+                    self.fetch_page(true);
+                }
+                result => self.err = failure(result),
+            },
+            // The contact list was requested anew since.
+            Pending::Page { listing, .. } if listing != self.listing => {}
+            Pending::Page { first, .. } => {
+                self.loading_page = false;
+                match result {
+                    Ok(Response::Page {
+                        contacts,
+                        next_cursor,
+                    }) => {
+                        if first {
+                            self.contacts.clear();
+                        }
+                        for info in contacts {
+                            // Contacts added since the previous page was
+                            // loaded may be part of this one.
+                            if self.contact(&info.name).is_none() {
+                                self.contacts.push(Contact {
+                                    info,
+                                    status: Status::Confirmed,
+                                });
+                            }
+                        }
+                        self.next_cursor = next_cursor;
+                    }
+                    result => self.err = failure(result),
+                }
+            }
+        }
+    }
+}
+
+/// Makes `number` the primary number of `info`, in place of the old one.
+fn set_primary(info: &mut ContactInfo, number: String) {
+    match info.numbers.iter_mut().find(|x| x.primary) {
+        Some(primary) => {
+            primary.display = number.clone();
+            primary.number = number;
+        }
+        None => info.numbers.insert(
+            0,
+            LabelledNumber {
+                label: DEFAULT_LABEL.to_owned(),
+                number: number.clone(),
+                display: number,
+                primary: true,
+            },
+        ),
+    }
+}
+
+/// `success` for [`Response::Success`], the reason of the failure otherwise.
+fn outcome(result: Result<Response, Error>, success: String) -> Result<String, String> {
+    match result {
+        Ok(Response::Success) => Ok(success),
+        result => Err(failure(result)),
+    }
+}
+
+/// Text shown for a failure or a response of an unexpected kind.
+fn failure(result: Result<Response, Error>) -> String {
+    match result {
+        Err(e) => describe(&e),
+        Ok(_) => "Unexpected response from server".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::codec::Codec;
+    use common::fragment::{self, MAX_DATAGRAM_SIZE};
+    use common::{Envelope, RequestId, PROTOCOL_VERSION};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    /// Receives the next request on `server`.
+    fn request(server: &UdpSocket) -> (SocketAddr, RequestId, Instruction) {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let (bytes, addr) = server.recv_from(&mut buf).unwrap();
+        let Envelope { id, body } = Codec::decode::<Envelope<Instruction>>(&buf[..bytes]).unwrap();
+        (addr, id, body)
+    }
+
+    /// Answers request `id` with `response`, and hands it to `session`.
+    fn answer(
+        server: &UdpSocket,
+        session: &mut Session,
+        request: (SocketAddr, RequestId),
+        response: Response,
+    ) {
+        let (addr, id) = request;
+        let bytes = Codec::Json.encode(&Envelope::new(id, response)).unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        for fragment in fragment::split(id, &bytes) {
+            server
+                .send_to(&Codec::Json.encode(&fragment).unwrap(), addr)
+                .unwrap();
+            let bytes = session.socket().recv(&mut buf).unwrap();
+            session.receive(Codec::decode::<Fragment>(&buf[..bytes]).unwrap());
+        }
+    }
+
+    fn page(names: &[&str], next_cursor: Option<&str>) -> Response {
+        let contacts = names
+            .iter()
+            .map(|name| ContactInfo {
+                name: name.to_string(),
+                numbers: vec![],
+                details: Default::default(),
+                groups: vec![],
+            })
+            .collect();
+        Response::Page {
+            contacts,
+            next_cursor: next_cursor.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn pages_of_a_previous_listing_are_dropped() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap().to_string();
+        let mut session = Session::connect(
+            "127.0.0.1:0",
+            &address,
+            RetryPolicy::default(),
+            "test",
+            None,
+        )
+        .unwrap();
+        session
+            .socket()
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let (addr, id, _) = request(&server);
+        let hello = Response::Hello {
+            versions: vec![PROTOCOL_VERSION],
+            capabilities: vec![],
+            codec: Codec::Json,
+        };
+        answer(&server, &mut session, (addr, id), hello);
+        let (_, groups, _) = request(&server);
+        let (_, all, _) = request(&server);
+        // The user picks a group before the first page arrived.
+        session.filter_group(Some("work".to_owned()));
+        let (_, work, instruction) = request(&server);
+        assert!(matches!(
+            instruction,
+            Instruction::ListUsers { group: Some(_), .. }
+        ));
+
+        answer(
+            &server,
+            &mut session,
+            (addr, groups),
+            Response::Groups(vec!["work".to_owned()]),
+        );
+        answer(&server, &mut session, (addr, work), page(&["Bob"], None));
+        answer(
+            &server,
+            &mut session,
+            (addr, all),
+            page(&["Alice", "Zed"], Some("Zed")),
+        );
+        let names: Vec<_> = session
+            .contacts()
+            .iter()
+            .map(|x| x.info.name.as_str())
+            .collect();
+        assert_eq!(names, ["Bob"]);
+        assert!(!session.has_more());
+        assert!(!session.loading_page());
+    }
+}
@@ -1,10 +1,25 @@
+use client_core::is_valid_number;
+use client_core::session::{self, Handshake, Session, Status};
+use common::retry::RetryPolicy;
 use common::*;
 use iced::button::{self, Button};
+use iced::container;
 use iced::scrollable::{self, Scrollable};
 use iced::text_input::{self, TextInput};
-use iced::{Application, Color, Column, Command, Container, Element, Length, Row, Settings, Text};
-use std::net::UdpSocket;
+use iced::{
+    Application, Color, Column, Command, Container, Element, Length, Row, Settings, Subscription,
+    Text,
+};
+use std::time::Instant;
 
+mod network;
+
+/// Name the client introduces itself with in the handshake.
+const CLIENT_NAME: &str = concat!("iced client ", env!("CARGO_PKG_VERSION"));
+
+// There is a single `App` for the whole run of the client, so the size
+// difference between the variants costs nothing.
+#[allow(clippy::large_enum_variant)]
 pub enum App {
     Loading {
         from_port_value: String,
@@ -15,6 +30,8 @@ pub enum App {
         port_input: text_input::State,
         ip_value: String,
         ip_input: text_input::State,
+        token_value: String,
+        token_input: text_input::State,
         button: button::State,
         err: String,
     },
@@ -22,26 +39,102 @@ pub enum App {
 }
 
 pub struct State {
+    session: Session,
     scroll: scrollable::State,
     add_button: button::State,
     fetch_button: button::State,
+    more_button: button::State,
+    lookup_button: button::State,
+    lookup_input: text_input::State,
+    lookup_value: String,
+    search_input: text_input::State,
 
+    /// Widgets of the contacts, in the order of [`Session::contacts`].
     contacts: Vec<Contact>,
     name_value: String,
     number_value: String,
     number_input: text_input::State,
     input: text_input::State,
-    socket: UdpSocket,
-    addr: String,
+    /// Buttons of the groups, in the order of [`Session::groups`].
+    groups: Vec<button::State>,
+    all_groups_button: button::State,
+    group_value: String,
+    group_input: text_input::State,
+    create_group_button: button::State,
+    rename_group_button: button::State,
+}
+
+impl State {
+    /// Keeps a widget for every contact and group of the session, widgets of
+    /// contacts that are still there keep their state.
+    fn sync(&mut self) {
+        let mut widgets = std::mem::take(&mut self.contacts);
+        self.contacts = self
+            .session
+            .contacts()
+            .iter()
+            .map(
+                |contact| match widgets.iter().position(|x| x.name == contact.info.name) {
+                    Some(i) => widgets.swap_remove(i),
+                    None => Contact::new(contact.info.name.clone()),
+                },
+            )
+            .collect();
+        self.groups
+            .resize_with(self.session.groups().len(), button::State::new);
+    }
 }
 
+/// Widgets of a contact, what they show comes from the [`session::Contact`]
+/// of the same name.
 pub struct Contact {
     name: String,
+    /// Primary number while it is edited.
     number: String,
+    /// Buttons of the numbers other than the primary one.
+    others: Vec<OtherNumber>,
+    new_label: String,
+    new_number: String,
+    label_input: text_input::State,
+    new_number_input: text_input::State,
+    add_number_button: button::State,
+    email_value: String,
+    organization_value: String,
+    notes_value: String,
+    email_input: text_input::State,
+    organization_input: text_input::State,
+    notes_input: text_input::State,
+    save_details_button: button::State,
+    details_error: String,
+    /// Buttons of the groups the contact belongs to.
+    groups: Vec<button::State>,
+    group_value: String,
+    group_input: text_input::State,
+    assign_button: button::State,
     state: ContactState,
     is_correct: bool,
 }
 
+/// Look of the group names shown on contacts.
+struct Chip;
+
+impl container::StyleSheet for Chip {
+    fn style(&self) -> container::Style {
+        container::Style {
+            text_color: Some(Color::WHITE),
+            background: Some(Color::from_rgb(0.3, 0.5, 0.8).into()),
+            border_radius: 10.0,
+            ..container::Style::default()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct OtherNumber {
+    primary_button: button::State,
+    remove_button: button::State,
+}
+
 #[derive(Debug, Clone)]
 pub enum ContactState {
     Idle {
@@ -59,9 +152,57 @@ pub enum ContactMessage {
     FinishEdition,
     Edited(String),
     Delete,
+    LabelChanged(String),
+    NewNumberChanged(String),
+    AddNumber,
+    MakePrimary(usize),
+    RemoveNumber(usize),
+    EmailChanged(String),
+    OrganizationChanged(String),
+    NotesChanged(String),
+    SaveDetails,
+    GroupChanged(String),
+    Assign,
+    Unassign(usize),
 }
 impl Contact {
-    fn update(&mut self, message: ContactMessage) {
+    fn new(name: String) -> Self {
+        Self {
+            state: ContactState::Idle {
+                edit_button: button::State::new(),
+            },
+            is_correct: true,
+            name,
+            number: String::new(),
+            others: vec![],
+            new_label: String::new(),
+            new_number: String::new(),
+            label_input: text_input::State::new(),
+            new_number_input: text_input::State::new(),
+            add_number_button: button::State::new(),
+            email_value: String::new(),
+            organization_value: String::new(),
+            notes_value: String::new(),
+            email_input: text_input::State::new(),
+            organization_input: text_input::State::new(),
+            notes_input: text_input::State::new(),
+            save_details_button: button::State::new(),
+            details_error: String::new(),
+            groups: vec![],
+            group_value: String::new(),
+            group_input: text_input::State::new(),
+            assign_button: button::State::new(),
+        }
+    }
+
+    /// Returns the instruction that has to be sent to the server, if any.
+    /// `info` is what the server knows about the contact.
+    fn update(&mut self, message: ContactMessage, info: &ContactInfo) -> Option<Instruction> {
+        let saved_number = info
+            .primary()
+            .map(|x| x.display.as_str())
+            .unwrap_or_default();
+        let others: Vec<&LabelledNumber> = info.numbers.iter().filter(|x| !x.primary).collect();
         match message {
             ContactMessage::Edit => {
                 let text_input = text_input::State::focused();
@@ -70,43 +211,172 @@ impl Contact {
                     number_input: text_input,
                     delete_button: button::State::new(),
                 };
+                self.number = saved_number.to_owned();
+                self.email_value = info.details.email.clone().unwrap_or_default();
+                self.organization_value = info.details.organization.clone().unwrap_or_default();
+                self.notes_value = info.details.notes.clone().unwrap_or_default();
+                self.details_error.clear();
+            }
+            ContactMessage::EmailChanged(email) => {
+                self.email_value = email;
+            }
+            ContactMessage::OrganizationChanged(organization) => {
+                self.organization_value = organization;
+            }
+            ContactMessage::NotesChanged(notes) => {
+                self.notes_value = notes;
+            }
+            ContactMessage::SaveDetails => {
+                let details = ContactDetails {
+                    email: Some(self.email_value.clone()),
+                    organization: Some(self.organization_value.clone()),
+                    notes: Some(self.notes_value.clone()),
+                }
+                .normalized();
+                match details.validate() {
+                    Ok(()) => {
+                        self.details_error.clear();
+                        if details != info.details {
+                            return Some(Instruction::EditDetails {
+                                key: self.name.clone(),
+                                details,
+                            });
+                        }
+                    }
+                    Err(e) => self.details_error = e,
+                }
             }
             ContactMessage::Delete => {}
+            ContactMessage::LabelChanged(label) => {
+                self.new_label = label;
+            }
+            ContactMessage::NewNumberChanged(number) => {
+                self.new_number = number;
+            }
+            ContactMessage::AddNumber => {
+                if is_valid_number(&self.new_number) {
+                    let label = std::mem::take(&mut self.new_label);
+                    return Some(Instruction::AddNumber {
+                        key: self.name.clone(),
+                        label: if label.is_empty() {
+                            "mobile".to_owned()
+                        } else {
+                            label
+                        },
+                        number: std::mem::take(&mut self.new_number),
+                    });
+                }
+            }
+            ContactMessage::MakePrimary(i) => {
+                return others.get(i).map(|other| Instruction::SetPrimaryNumber {
+                    key: self.name.clone(),
+                    number: other.number.clone(),
+                });
+            }
+            ContactMessage::GroupChanged(group) => {
+                self.group_value = group;
+            }
+            ContactMessage::Assign => {
+                let group = self.group_value.trim().to_owned();
+                if !group.is_empty() {
+                    self.group_value.clear();
+                    return Some(Instruction::AssignGroup {
+                        key: self.name.clone(),
+                        group,
+                    });
+                }
+            }
+            ContactMessage::Unassign(i) => {
+                return info.groups.get(i).map(|group| Instruction::UnassignGroup {
+                    key: self.name.clone(),
+                    group: group.clone(),
+                });
+            }
+            ContactMessage::RemoveNumber(i) => {
+                return others.get(i).map(|other| Instruction::RemoveNumber {
+                    key: self.name.clone(),
+                    number: other.number.clone(),
+                });
+            }
             ContactMessage::Edited(number) => {
                 self.number = number;
             }
             ContactMessage::FinishEdition => {
-                if !self.number.is_empty()
-                    && phonenumber::parse(None, &self.number)
-                        .map(|x| phonenumber::is_valid(&x))
-                        .unwrap_or_else(|_| false)
-                {
+                if is_valid_number(&self.number) {
                     self.is_correct = true;
                     self.state = ContactState::Idle {
                         edit_button: button::State::new(),
+                    };
+                    if self.number != saved_number {
+                        return Some(Instruction::EditNumber {
+                            key: self.name.clone(),
+                            number: self.number.clone(),
+                        });
                     }
                 } else {
                     self.is_correct = self.number.is_empty();
                 }
             }
         }
+        None
     }
 
-    fn view(&mut self) -> Element<ContactMessage> {
+    fn view(&mut self, contact: &session::Contact) -> Element<'_, ContactMessage> {
+        let info = &contact.info;
+        let others: Vec<&LabelledNumber> = info.numbers.iter().filter(|x| !x.primary).collect();
+        self.others.resize_with(others.len(), OtherNumber::default);
+        self.groups
+            .resize_with(info.groups.len(), button::State::new);
+        let number = info
+            .primary()
+            .map(|x| x.display.as_str())
+            .unwrap_or_default();
+        let mut column = Column::new().spacing(10);
         match &mut self.state {
-            ContactState::Idle { edit_button } => Row::new()
-                .spacing(20)
-                .push(
-                    Text::new(&format!("{}: {}", self.name, self.number))
-                        .horizontal_alignment(iced::HorizontalAlignment::Left),
-                )
-                .push(
-                    Button::new(edit_button, Text::new("Edit"))
-                        .on_press(ContactMessage::Edit)
-                        .padding(10),
-                )
-                .align_items(iced::Align::Start)
-                .into(),
+            ContactState::Idle { edit_button } => {
+                column = column.push(
+                    Row::new()
+                        .spacing(20)
+                        .push(
+                            Text::new(format!("{}: {}", self.name, number))
+                                .horizontal_alignment(iced::HorizontalAlignment::Left),
+                        )
+                        .push(
+                            Button::new(edit_button, Text::new("Edit"))
+                                .on_press(ContactMessage::Edit)
+                                .padding(10),
+                        )
+                        .push(match &contact.status {
+                            Status::Pending => Text::new("Saving...").color([0.7, 0.7, 0.7]),
+                            Status::Confirmed => {
+                                Text::new("Saved").color(Color::from_rgb(0.0, 0.6, 0.0))
+                            }
+                            Status::Failed(message) => Text::new(format!("Failed: {}", message))
+                                .color(Color::from_rgb(1.0, 0.0, 0.0)),
+                        })
+                        .align_items(iced::Align::Start),
+                );
+                let details = [
+                    &info.details.organization,
+                    &info.details.email,
+                    &info.details.notes,
+                ];
+                for detail in details.into_iter().flatten() {
+                    column = column.push(Text::new(detail).size(16).color([0.4, 0.4, 0.4]));
+                }
+                if !info.groups.is_empty() {
+                    column = column.push(info.groups.iter().fold(
+                        Row::new().spacing(10),
+                        |row, group| {
+                            row.push(
+                                Container::new(Text::new(group).size(16))
+                                    .padding(5)
+                                    .style(Chip),
+                            )
+                        },
+                    ));
+                }
+            }
             ContactState::Editing {
                 number_input,
                 delete_button,
@@ -139,9 +409,130 @@ impl Contact {
                         Text::new("Incorrect phone number").color(Color::from_rgb(1.0, 0.0, 0.0)),
                     );
                 }
-                row.into()
+                let mut add_button =
+                    Button::new(&mut self.add_number_button, Text::new("Add number")).padding(10);
+                if is_valid_number(&self.new_number) {
+                    add_button = add_button.on_press(ContactMessage::AddNumber);
+                }
+                column = column.push(row).push(
+                    Row::new()
+                        .spacing(20)
+                        .align_items(iced::Align::Center)
+                        .push(
+                            TextInput::new(
+                                &mut self.label_input,
+                                "Label, e.g. work",
+                                &self.new_label,
+                                ContactMessage::LabelChanged,
+                            )
+                            .padding(10),
+                        )
+                        .push(
+                            TextInput::new(
+                                &mut self.new_number_input,
+                                "Another phone number",
+                                &self.new_number,
+                                ContactMessage::NewNumberChanged,
+                            )
+                            .on_submit(ContactMessage::AddNumber)
+                            .padding(10),
+                        )
+                        .push(add_button),
+                );
+                column = column.push(
+                    Row::new()
+                        .spacing(20)
+                        .align_items(iced::Align::Center)
+                        .push(
+                            TextInput::new(
+                                &mut self.email_input,
+                                "Email",
+                                &self.email_value,
+                                ContactMessage::EmailChanged,
+                            )
+                            .padding(10),
+                        )
+                        .push(
+                            TextInput::new(
+                                &mut self.organization_input,
+                                "Organization",
+                                &self.organization_value,
+                                ContactMessage::OrganizationChanged,
+                            )
+                            .padding(10),
+                        )
+                        .push(
+                            TextInput::new(
+                                &mut self.notes_input,
+                                "Notes",
+                                &self.notes_value,
+                                ContactMessage::NotesChanged,
+                            )
+                            .on_submit(ContactMessage::SaveDetails)
+                            .padding(10),
+                        )
+                        .push(
+                            Button::new(&mut self.save_details_button, Text::new("Save details"))
+                                .on_press(ContactMessage::SaveDetails)
+                                .padding(10),
+                        ),
+                );
+                if !self.details_error.is_empty() {
+                    column = column
+                        .push(Text::new(&self.details_error).color(Color::from_rgb(1.0, 0.0, 0.0)));
+                }
+                let chips = self.groups.iter_mut().zip(&info.groups).enumerate().fold(
+                    Row::new().spacing(10).align_items(iced::Align::Center),
+                    |row, (i, (button, group))| {
+                        row.push(
+                            Button::new(button, Text::new(format!("{} x", group)))
+                                .on_press(ContactMessage::Unassign(i))
+                                .padding(5),
+                        )
+                    },
+                );
+                column = column.push(
+                    chips
+                        .push(
+                            TextInput::new(
+                                &mut self.group_input,
+                                "Group",
+                                &self.group_value,
+                                ContactMessage::GroupChanged,
+                            )
+                            .on_submit(ContactMessage::Assign)
+                            .padding(5),
+                        )
+                        .push(
+                            Button::new(&mut self.assign_button, Text::new("Add to group"))
+                                .on_press(ContactMessage::Assign)
+                                .padding(5),
+                        ),
+                );
             }
         }
+        for (i, (buttons, other)) in self.others.iter_mut().zip(others).enumerate() {
+            column = column.push(
+                Row::new()
+                    .spacing(20)
+                    .align_items(iced::Align::Center)
+                    .push(Text::new(format!("{}: {}", other.label, other.display)))
+                    .push(
+                        Button::new(&mut buttons.primary_button, Text::new("Make primary"))
+                            .on_press(ContactMessage::MakePrimary(i))
+                            .padding(5),
+                    )
+                    .push(
+                        Button::new(
+                            &mut buttons.remove_button,
+                            Text::new("Remove").color(Color::from_rgb(1.0, 0.0, 0.0)),
+                        )
+                        .on_press(ContactMessage::RemoveNumber(i))
+                        .padding(5),
+                    ),
+            );
+        }
+        column.into()
     }
 }
 
@@ -159,11 +550,22 @@ pub enum Message {
     InputChanged2(String),
     InputChanged3(String),
     InputChanged4(String),
+    TokenChanged(String),
     Continue,
     ContactMessage(usize, ContactMessage),
     AddUser,
-    GetAllUsers,
+    FetchContacts,
+    LoadMore,
     DeleteUsers,
+    LookupChanged(String),
+    Lookup,
+    SearchChanged(String),
+    /// Limits the contact list to a group, `None` shows all contacts.
+    FilterGroup(Option<String>),
+    GroupNameChanged(String),
+    CreateGroup,
+    RenameGroup,
+    Network(network::Event),
 }
 
 fn empty_message<'a>(message: &str) -> Element<'a, Message> {
@@ -198,6 +600,8 @@ impl Application for App {
                 from_ip_value: String::new(),
                 from_port_input: text_input::State::new(),
                 from_port_value: String::new(),
+                token_input: text_input::State::new(),
+                token_value: String::new(),
             },
             Command::none(),
         )
@@ -212,78 +616,70 @@ impl Application for App {
         _: &mut iced::Clipboard,
     ) -> Command<Self::Message> {
         match self {
-            Self::Loaded(state) => match message {
-                Message::InputChanged(input) => {
-                    state.name_value = input;
-                }
-                Message::InputChanged2(input) => {
-                    state.number_value = input;
-                }
-                Message::AddUser => {
-                    let instruction = serde_json::to_vec(&Instruction::AddPhoneNumber {
-                        key: state.name_value.clone(),
-                        number: state.number_value.clone(),
-                    })
-                    .unwrap();
-                    if state.contacts.iter().any(|x| x.name == state.name_value) {
-                        return Command::none();
+            Self::Loaded(state) => {
+                match message {
+                    Message::InputChanged(input) => {
+                        state.name_value = input;
+                        state.session.clear_error();
                     }
-
-                    state.socket.send_to(&instruction, &state.addr).unwrap();
-                    state.contacts.push(Contact {
-                        state: ContactState::Idle {
-                            edit_button: button::State::new(),
-                        },
-                        is_correct: true,
-                        name: state.name_value.clone(),
-                        number: state.number_value.clone(),
-                    });
-                    state.name_value.clear();
-                    state.number_value.clear();
-                }
-                Message::ContactMessage(i, ContactMessage::Delete) => {
-                    if state.contacts.len() > i {
-                        let contact = state.contacts.remove(i);
-                        let instruction =
-                            serde_json::to_vec(&Instruction::DeleteUser { key: contact.name })
-                                .unwrap();
-                        state.socket.send_to(&instruction, &state.addr).unwrap();
+                    Message::InputChanged2(input) => {
+                        state.number_value = input;
                     }
-                }
-                Message::ContactMessage(i, message) => {
-                    if let Some(contact) = state.contacts.get_mut(i) {
-                        contact.update(message);
+                    Message::AddUser => {
+                        let name = std::mem::take(&mut state.name_value);
+                        let number = std::mem::take(&mut state.number_value);
+                        state.session.add(name, number);
                     }
-                }
-                Message::GetAllUsers => {
-                    let instruction = serde_json::to_vec(&Instruction::GetAllUsers).unwrap();
-                    state.socket.send_to(&instruction, &state.addr).unwrap();
-
-                    let mut vec = vec![0u8; 8 * 1024];
-
-                    let (bytes, _) = state.socket.recv_from(&mut vec).unwrap();
-
-                    let contacts = serde_json::from_slice::<Response>(&vec[..bytes]);
-                    match contacts {
-                        Ok(Response::AllUsers(contacts)) => {
-                            state.contacts.clear();
-
-                            for (contact, number) in contacts {
-                                state.contacts.push(Contact {
-                                    state: ContactState::Idle {
-                                        edit_button: button::State::new(),
-                                    },
-                                    is_correct: true,
-                                    name: contact.clone(),
-                                    number: number.clone(),
-                                })
+                    Message::ContactMessage(i, message) if i < state.contacts.len() => {
+                        let name = state.contacts[i].name.clone();
+                        if let ContactMessage::Delete = message {
+                            state.session.delete(name);
+                        } else if let Some(contact) = state.session.contact(&name) {
+                            match state.contacts[i].update(message, &contact.info) {
+                                Some(Instruction::EditNumber { number, .. }) => {
+                                    state.session.edit_number(name, number)
+                                }
+                                Some(instruction) => state.session.change(name, instruction),
+                                None => {}
                             }
                         }
-                        _ => unreachable!(),
                     }
+                    Message::SearchChanged(query) => {
+                        state.session.set_search(query, Instant::now());
+                    }
+                    Message::LookupChanged(input) => {
+                        state.lookup_value = input;
+                    }
+                    Message::Lookup => {
+                        let key = state.lookup_value.clone();
+                        state.session.lookup(key);
+                    }
+                    Message::FetchContacts => state.session.fetch_page(true),
+                    Message::LoadMore => state.session.load_more(),
+                    Message::FilterGroup(group) => state.session.filter_group(group),
+                    Message::GroupNameChanged(name) => {
+                        state.group_value = name;
+                    }
+                    Message::CreateGroup if !state.group_value.trim().is_empty() => {
+                        let name = std::mem::take(&mut state.group_value);
+                        state.session.create_group(name);
+                    }
+                    Message::RenameGroup if !state.group_value.trim().is_empty() => {
+                        if let Some(name) = state.session.group_filter().map(str::to_owned) {
+                            let new_name = std::mem::take(&mut state.group_value);
+                            state.session.rename_group(name, new_name);
+                        }
+                    }
+                    Message::Network(network::Event::Fragment(fragment)) => {
+                        state.session.receive(fragment);
+                    }
+                    Message::Network(network::Event::Tick) => {
+                        state.session.tick(Instant::now());
+                    }
+                    _ => (),
                 }
-                _ => (),
-            },
+                state.sync();
+            }
             Self::Loading {
                 port_value,
                 port_input: _,
@@ -294,6 +690,8 @@ impl Application for App {
                 from_ip_value,
                 from_port_input: _,
                 from_port_value,
+                token_input: _,
+                token_value,
                 err,
             } => match message {
                 Message::InputChanged3(ip) => {
@@ -312,41 +710,52 @@ impl Application for App {
                     *from_port_value = port;
                     err.clear();
                 }
+                Message::TokenChanged(token) => {
+                    *token_value = token;
+                }
 
                 Message::Continue => {
-                    let socket = UdpSocket::bind(format!("{}:{}", from_ip_value, from_port_value));
-                    let socket = match socket {
+                    let token = Some(token_value.clone()).filter(|token| !token.is_empty());
+                    let session = Session::connect(
+                        &format!("{}:{}", from_ip_value, from_port_value),
+                        &format!("{}:{}", ip_value, port_value),
+                        RetryPolicy::default(),
+                        CLIENT_NAME,
+                        token,
+                    );
+                    let session = match session {
                         Ok(x) => x,
                         Err(e) => {
                             *err = format!(
-                                "Failed to bind socket to `{}:{}`: {}",
-                                from_ip_value, from_port_value, e
+                                "Failed to connect `{}:{}` to `{}:{}`: {}",
+                                from_ip_value, from_port_value, ip_value, port_value, e
                             );
                             return Command::none();
                         }
                     };
-                    match socket.connect(format!("{}:{}", ip_value, port_value)) {
-                        Ok(_) => {
-                            *self = Self::Loaded(State {
-                                add_button: button::State::new(),
-                                input: text_input::State::new(),
-                                scroll: scrollable::State::new(),
-                                name_value: "".to_string(),
-                                number_input: text_input::State::new(),
-                                number_value: "".to_string(),
-                                contacts: vec![],
-                                fetch_button: button::State::new(),
-                                socket,
-                                addr: format!("{}:{}", ip_value, port_value),
-                            })
-                        }
-                        Err(e) => {
-                            *err = format!(
-                                "Failed to connect socket to `{}:{}`: {}",
-                                ip_value, port_value, e
-                            );
-                        }
-                    }
+                    let state = State {
+                        session,
+                        add_button: button::State::new(),
+                        input: text_input::State::new(),
+                        scroll: scrollable::State::new(),
+                        name_value: "".to_string(),
+                        number_input: text_input::State::new(),
+                        number_value: "".to_string(),
+                        contacts: vec![],
+                        fetch_button: button::State::new(),
+                        lookup_button: button::State::new(),
+                        lookup_input: text_input::State::new(),
+                        lookup_value: String::new(),
+                        search_input: text_input::State::new(),
+                        groups: vec![],
+                        all_groups_button: button::State::new(),
+                        group_value: String::new(),
+                        group_input: text_input::State::new(),
+                        create_group_button: button::State::new(),
+                        rename_group_button: button::State::new(),
+                        more_button: button::State::new(),
+                    };
+                    *self = Self::Loaded(state);
                 }
                 _ => (),
             },
@@ -354,6 +763,18 @@ impl Application for App {
         Command::none()
     }
 
+    fn subscription(&self) -> Subscription<Self::Message> {
+        match self {
+            // The scrollable does not report its offset, so further pages are
+            // loaded with the "Load more" button at the end of the list.
+            Self::Loaded(state) => {
+                Subscription::from_recipe(network::Responses::new(state.session.socket()))
+                    .map(Message::Network)
+            }
+            Self::Loading { .. } => Subscription::none(),
+        }
+    }
+
     fn view(&mut self) -> iced::Element<'_, Self::Message> {
         let title = Text::new("phone numbers")
             .width(Length::Fill)
@@ -362,6 +783,27 @@ impl Application for App {
             .horizontal_alignment(iced::HorizontalAlignment::Center);
         match self {
             Self::Loaded(state) => {
+                let status = match state.session.handshake() {
+                    Handshake::Done => None,
+                    Handshake::Pending => {
+                        Some(Text::new("Connecting to server...").color([0.7, 0.7, 0.7]))
+                    }
+                    Handshake::Refused(reason) => Some(
+                        Text::new(format!("Could not connect: {}", reason))
+                            .color(Color::from_rgb(1.0, 0.0, 0.0)),
+                    ),
+                };
+                if let Some(status) = status {
+                    let content = Column::new()
+                        .max_width(640)
+                        .spacing(20)
+                        .push(title)
+                        .push(status);
+                    return Container::new(content)
+                        .width(Length::Fill)
+                        .center_x()
+                        .into();
+                }
                 let contact_name = TextInput::new(
                     &mut state.input,
                     "User name",
@@ -371,11 +813,9 @@ impl Application for App {
                 .padding(15)
                 .size(30);
                 let mut content = Column::new();
-                let exists = state.contacts.iter().any(|x| x.name == state.name_value);
+                let exists = state.session.contact(&state.name_value).is_some();
 
-                let parsed = phonenumber::parse(None, &state.number_value)
-                    .map(|x| phonenumber::is_valid(&x))
-                    .unwrap_or_else(|_| false);
+                let parsed = is_valid_number(&state.number_value);
 
                 let number = TextInput::new(
                     &mut state.number_input,
@@ -389,14 +829,18 @@ impl Application for App {
                     state
                         .contacts
                         .iter_mut()
+                        .zip(state.session.contacts())
                         .enumerate()
-                        .fold(Column::new().spacing(20), |column, (i, contact)| {
-                            column.push(
-                                contact
-                                    .view()
-                                    .map(move |msg| Message::ContactMessage(i, msg)),
-                            )
-                        })
+                        .fold(
+                            Column::new().spacing(20),
+                            |column, (i, (widget, contact))| {
+                                column.push(
+                                    widget
+                                        .view(contact)
+                                        .map(move |msg| Message::ContactMessage(i, msg)),
+                                )
+                            },
+                        )
                         .into()
                 } else {
                     empty_message("You do not have any contacts yet...")
@@ -405,7 +849,13 @@ impl Application for App {
                 content = content.max_width(640).spacing(20).push(title);
                 if exists {
                     content = content.push(
-                        Text::new("This user is already registered!")
+                        Text::new("This user is already registered, adding replaces the number!")
+                            .color(Color::from_rgb(1.0, 0.0, 0.0)),
+                    );
+                }
+                if !state.session.error().is_empty() {
+                    content = content.push(
+                        Text::new(format!("Error: {}", state.session.error()))
                             .color(Color::from_rgb(1.0, 0.0, 0.0)),
                     );
                 }
@@ -418,20 +868,125 @@ impl Application for App {
                     content = content.push(
                         Button::new(
                             &mut state.add_button,
-                            Text::new("Add contact")
-                                .horizontal_alignment(iced::HorizontalAlignment::Center),
+                            Text::new(if exists {
+                                "Replace number"
+                            } else {
+                                "Add contact"
+                            })
+                            .horizontal_alignment(iced::HorizontalAlignment::Center),
                         )
                         .on_press(Message::AddUser),
                     );
                 }
+                let lookup = TextInput::new(
+                    &mut state.lookup_input,
+                    "Look up number by name",
+                    &state.lookup_value,
+                    Message::LookupChanged,
+                )
+                .on_submit(Message::Lookup)
+                .padding(10);
+                let mut lookup_button =
+                    Button::new(&mut state.lookup_button, Text::new("Look up")).padding(10);
+                if !state.lookup_value.is_empty() {
+                    lookup_button = lookup_button.on_press(Message::Lookup);
+                }
+                content = content.push(
+                    Row::new()
+                        .spacing(20)
+                        .align_items(iced::Align::Center)
+                        .push(lookup)
+                        .push(lookup_button),
+                );
+                if !state.session.lookup_result().is_empty() {
+                    content = content.push(Text::new(state.session.lookup_result()));
+                }
+                content = content.push(
+                    TextInput::new(
+                        &mut state.search_input,
+                        "Search names and numbers",
+                        state.session.search(),
+                        Message::SearchChanged,
+                    )
+                    .padding(10),
+                );
+                let results = state.session.search_results();
+                if state.session.search_fuzzy() && !results.is_empty() {
+                    content = content.push(Text::new("Did you mean:").color([0.7, 0.7, 0.7]));
+                }
+                for contact in results {
+                    let number = contact.primary().map(|x| x.display.as_str());
+                    content = content.push(Text::new(format!(
+                        "{}: {}",
+                        contact.name,
+                        number.unwrap_or("no number")
+                    )));
+                }
                 content = content.push(
                     Button::new(
                         &mut state.fetch_button,
                         Text::new("Fetch contacts from server"),
                     )
-                    .on_press(Message::GetAllUsers),
+                    .on_press(Message::FetchContacts),
+                );
+                let group_filter = state.session.group_filter();
+                let filter = state.groups.iter_mut().zip(state.session.groups()).fold(
+                    Row::new()
+                        .spacing(10)
+                        .align_items(iced::Align::Center)
+                        .push(
+                            Button::new(&mut state.all_groups_button, Text::new("All"))
+                                .on_press(Message::FilterGroup(None))
+                                .padding(5),
+                        ),
+                    |row, (button, group)| {
+                        let mut label = Text::new(group);
+                        if group_filter == Some(group.as_str()) {
+                            label = label.color(Color::from_rgb(0.3, 0.5, 0.8));
+                        }
+                        row.push(
+                            Button::new(button, label)
+                                .on_press(Message::FilterGroup(Some(group.clone())))
+                                .padding(5),
+                        )
+                    },
+                );
+                let mut rename_button =
+                    Button::new(&mut state.rename_group_button, Text::new("Rename group"))
+                        .padding(5);
+                if group_filter.is_some() {
+                    rename_button = rename_button.on_press(Message::RenameGroup);
+                }
+                content = content.push(filter).push(
+                    Row::new()
+                        .spacing(10)
+                        .align_items(iced::Align::Center)
+                        .push(
+                            TextInput::new(
+                                &mut state.group_input,
+                                "Group name",
+                                &state.group_value,
+                                Message::GroupNameChanged,
+                            )
+                            .on_submit(Message::CreateGroup)
+                            .padding(5),
+                        )
+                        .push(
+                            Button::new(&mut state.create_group_button, Text::new("Create group"))
+                                .on_press(Message::CreateGroup)
+                                .padding(5),
+                        )
+                        .push(rename_button),
                 );
                 content = content.push(contacts);
+                if state.session.loading_page() {
+                    content = content.push(Text::new("Loading...").color([0.7, 0.7, 0.7]));
+                } else if state.session.has_more() {
+                    content = content.push(
+                        Button::new(&mut state.more_button, Text::new("Load more"))
+                            .on_press(Message::LoadMore),
+                    );
+                }
                 Scrollable::new(&mut state.scroll)
                     .padding(40)
                     .push(Container::new(content).width(Length::Fill).center_x())
@@ -446,6 +1001,8 @@ impl Application for App {
                 ip_value,
                 port_input,
                 port_value,
+                token_input,
+                token_value,
                 err,
                 button,
             } => {
@@ -479,6 +1036,15 @@ impl Application for App {
                 )
                 .padding(15)
                 .size(30);
+                let token = TextInput::new(
+                    token_input,
+                    "Access token (optional)",
+                    token_value,
+                    Message::TokenChanged,
+                )
+                .password()
+                .padding(15)
+                .size(30);
                 let continue_btn = Button::new(button, Text::new("Connect to phone numbers DB"))
                     .on_press(Message::Continue)
                     .padding(10);
@@ -491,6 +1057,7 @@ impl Application for App {
                     .push(Text::new("Enter IP & port of the server: "))
                     .push(ip)
                     .push(port)
+                    .push(token)
                     .push(continue_btn);
                 if !err.is_empty() {
                     content = content.push(
@@ -507,5 +1074,11 @@ impl Application for App {
 }
 
 fn main() {
+    // Retransmissions and dropped responses are worth seeing while the
+    // client runs in a terminal, `RUST_LOG` silences them.
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Debug)
+        .parse_default_env()
+        .init();
     App::run(Settings::default()).unwrap();
 }
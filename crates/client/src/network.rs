@@ -0,0 +1,79 @@
+use common::codec::Codec;
+use common::fragment::{Fragment, MAX_DATAGRAM_SIZE};
+use iced::futures::channel::mpsc;
+use iced::futures::stream::{BoxStream, StreamExt};
+use iced_native::subscription::{EventStream, Recipe};
+use iced_native::{event, Hasher};
+use log::warn;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the receiving thread checks whether anybody still listens to it.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Fragment(Fragment),
+    /// Emitted every [`POLL_INTERVAL`] so the application can resend requests
+    /// that timed out and send searches once typing stopped.
+    Tick,
+}
+
+/// Subscription producing every response fragment the server sends to `socket`.
+///
+/// `recv_from` blocks, so datagrams are read on a dedicated thread and handed
+/// over to the application through a channel. The thread stops once the
+/// subscription is dropped.
+pub struct Responses {
+    socket: Arc<UdpSocket>,
+}
+
+impl Responses {
+    pub fn new(socket: Arc<UdpSocket>) -> Self {
+        Self { socket }
+    }
+}
+
+impl Recipe<Hasher, (iced_native::Event, event::Status)> for Responses {
+    type Output = Event;
+
+    fn hash(&self, state: &mut Hasher) {
+        use std::hash::Hash;
+
+        struct Marker;
+        std::any::TypeId::of::<Marker>().hash(state);
+        self.socket.local_addr().ok().hash(state);
+    }
+
+    fn stream(self: Box<Self>, _input: EventStream) -> BoxStream<'static, Self::Output> {
+        let (sender, receiver) = mpsc::unbounded();
+        let socket = self.socket;
+        std::thread::spawn(move || {
+            if let Err(e) = socket.set_read_timeout(Some(POLL_INTERVAL)) {
+                warn!("Failed to set socket read timeout: {}", e);
+                return;
+            }
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let mut last_tick = Instant::now();
+            while !sender.is_closed() {
+                if last_tick.elapsed() >= POLL_INTERVAL {
+                    last_tick = Instant::now();
+                    let _ = sender.unbounded_send(Event::Tick);
+                }
+                match socket.recv_from(&mut buf) {
+                    Ok((bytes, _)) => match Codec::decode::<Fragment>(&buf[..bytes]) {
+                        Ok(fragment) => {
+                            let _ = sender.unbounded_send(Event::Fragment(fragment));
+                        }
+                        Err(e) => warn!("Dropping malformed fragment: {}", e),
+                    },
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(e) => warn!("Failed to receive response: {}", e),
+                }
+            }
+        });
+        receiver.boxed()
+    }
+}
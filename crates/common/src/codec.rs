@@ -0,0 +1,183 @@
+use crate::{Envelope, RequestId};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// First byte of every datagram encoded with [`Codec::Binary`]. JSON documents
+/// never start with it, so the codec of a datagram can always be told from
+/// its content.
+pub const BINARY_TAG: u8 = 0xB1;
+
+/// Wire encoding of datagrams.
+///
+/// Clients list the codecs they support in `Instruction::Hello` and the server
+/// picks one for the session. Both sides decode whatever arrives, the codec
+/// only decides how datagrams are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Codec {
+    #[default]
+    Json,
+    /// Compact bincode encoding, prefixed with [`BINARY_TAG`].
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Binary(bincode::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Json(e) => write!(f, "{}", e),
+            Self::Binary(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+impl Codec {
+    /// Codec `bytes` were encoded with.
+    pub fn detect(bytes: &[u8]) -> Self {
+        match bytes.first() {
+            Some(&BINARY_TAG) => Self::Binary,
+            _ => Self::Json,
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Self::Json => serde_json::to_vec(value).map_err(CodecError::Json),
+            Self::Binary => {
+                let mut bytes = vec![BINARY_TAG];
+                bincode::serialize_into(&mut bytes, value).map_err(CodecError::Binary)?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    /// Decodes `bytes` with the codec they were encoded with.
+    pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, CodecError> {
+        match Self::detect(bytes) {
+            Self::Json => serde_json::from_slice(bytes).map_err(CodecError::Json),
+            Self::Binary => bincode::deserialize(&bytes[1..]).map_err(CodecError::Binary),
+        }
+    }
+
+    /// Extracts the request id of an encoded envelope without decoding its
+    /// body, which may be of a kind this side does not know.
+    pub fn decode_id(bytes: &[u8]) -> Option<RequestId> {
+        match Self::detect(bytes) {
+            Self::Json => serde_json::from_slice::<Envelope<IgnoredAny>>(bytes)
+                .ok()
+                .map(|envelope| envelope.id),
+            // The id is the first field, bincode ignores the bytes after it.
+            Self::Binary => bincode::deserialize::<RequestId>(&bytes[1..]).ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ContactDetails, ContactInfo, ErrorKind, Instruction, LabelledNumber, Response};
+
+    const CODECS: [Codec; 2] = [Codec::Json, Codec::Binary];
+
+    /// Decodes what `codec` encoded from `value`, compared by their debug
+    /// output since messages do not implement `PartialEq`.
+    fn round_trip<T: Serialize + DeserializeOwned + fmt::Debug>(codec: Codec, value: T) {
+        let bytes = codec.encode(&value).unwrap();
+        assert_eq!(Codec::detect(&bytes), codec);
+        let decoded: T = Codec::decode(&bytes).unwrap();
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", value));
+    }
+
+    #[test]
+    fn codec_is_detected() {
+        assert_eq!(Codec::detect(&[BINARY_TAG, 1, 2]), Codec::Binary);
+        assert_eq!(Codec::detect(b"{\"id\":1}"), Codec::Json);
+        assert_eq!(Codec::detect(&[]), Codec::Json);
+    }
+
+    #[test]
+    fn envelopes_survive_a_round_trip() {
+        let contact = ContactInfo {
+            name: "Zoë".to_owned(),
+            numbers: vec![LabelledNumber {
+                label: "mobile".to_owned(),
+                number: "+16502530000".to_owned(),
+                display: "+1 650 253 0000".to_owned(),
+                primary: true,
+            }],
+            details: ContactDetails {
+                email: Some("zoe@example.com".to_owned()),
+                organization: None,
+                notes: None,
+            },
+            groups: vec!["friends".to_owned()],
+        };
+        for codec in CODECS {
+            round_trip(
+                codec,
+                Envelope::new(
+                    7,
+                    Instruction::Hello {
+                        protocol_version: 1,
+                        client_name: "test".to_owned(),
+                        codecs: CODECS.to_vec(),
+                        token: None,
+                    },
+                ),
+            );
+            round_trip(
+                codec,
+                Envelope::new(
+                    u64::MAX,
+                    Instruction::ListUsers {
+                        cursor: Some("Ann".to_owned()),
+                        limit: 50,
+                        group: None,
+                    },
+                ),
+            );
+            round_trip(
+                codec,
+                Envelope::new(
+                    8,
+                    Response::Page {
+                        contacts: vec![contact.clone()],
+                        next_cursor: None,
+                    },
+                ),
+            );
+            round_trip(
+                codec,
+                Envelope::new(
+                    9,
+                    Response::Fail {
+                        kind: ErrorKind::NotFound,
+                        message: "User 'Ann' does not exist".to_owned(),
+                    },
+                ),
+            );
+        }
+    }
+
+    #[test]
+    fn id_is_read_from_unknown_instructions() {
+        let json = br#"{"id":42,"body":{"Teleport":{"to":"Mars"}}}"#;
+        // A variant index past those of `Instruction`, with a body of its own.
+        let binary = Codec::Binary
+            .encode(&Envelope::new(42 as RequestId, (u32::MAX, "Mars")))
+            .unwrap();
+        for bytes in [&json[..], &binary[..]] {
+            assert!(Codec::decode::<Envelope<Instruction>>(bytes).is_err());
+            assert_eq!(Codec::decode_id(bytes), Some(42));
+        }
+        assert_eq!(Codec::decode_id(b"not json"), None);
+        assert_eq!(Codec::decode_id(&[BINARY_TAG, 1]), None);
+    }
+}
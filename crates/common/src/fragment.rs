@@ -0,0 +1,237 @@
+use crate::codec::Codec;
+use crate::RequestId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Largest encoded size of a fragment, so every datagram of a response fits
+/// the usual path MTU.
+pub const FRAGMENT_SIZE: usize = 1024;
+
+/// Largest payload a UDP datagram can carry, receive buffers should be this big.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Piece of an encoded response.
+///
+/// Responses may be larger than a datagram, so the server splits every
+/// encoded `Envelope<Response>` into fragments that encode to at most
+/// [`FRAGMENT_SIZE`] bytes. The client collects them with a [`Reassembler`]
+/// and asks for the missing ones with `Instruction::ResendFragments`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fragment {
+    /// Request the response answers.
+    pub id: RequestId,
+    pub index: u32,
+    pub count: u32,
+    pub data: Vec<u8>,
+}
+
+/// Splits the encoded response `bytes` into fragments, which are meant to be
+/// encoded with the same codec as the response. The split only depends on
+/// `bytes`, so fragments can be split again to be resent.
+pub fn split(id: RequestId, bytes: &[u8]) -> Vec<Fragment> {
+    let codec = Codec::detect(bytes);
+    // Encoded size of a fragment without data, with the widest index and count.
+    let empty = Fragment {
+        id,
+        index: u32::MAX,
+        count: u32::MAX,
+        data: vec![],
+    };
+    let overhead = codec.encode(&empty).map_or(0, |x| x.len());
+    let budget = FRAGMENT_SIZE.saturating_sub(overhead);
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < bytes.len() || chunks.is_empty() {
+        let mut end = start;
+        let mut size = 0;
+        while let Some(&byte) = bytes.get(end) {
+            size += encoded_len(codec, byte);
+            // Every fragment carries at least one byte, however small the budget.
+            if size > budget && end > start {
+                break;
+            }
+            end += 1;
+        }
+        chunks.push(&bytes[start..end]);
+        start = end;
+    }
+    let count = chunks.len() as u32;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| Fragment {
+            id,
+            index: index as u32,
+            count,
+            data: data.to_vec(),
+        })
+        .collect()
+}
+
+/// Size of `byte` as an element of [`Fragment::data`] encoded with `codec`.
+/// JSON writes it as a number followed by a comma, the last one has none.
+fn encoded_len(codec: Codec, byte: u8) -> usize {
+    match codec {
+        Codec::Binary => 1,
+        Codec::Json => match byte {
+            0..=9 => 2,
+            10..=99 => 3,
+            _ => 4,
+        },
+    }
+}
+
+struct Partial {
+    received: usize,
+    chunks: Vec<Option<Vec<u8>>>,
+}
+
+/// Collects fragments until a response is complete.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<RequestId, Partial>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stores `fragment`, returns the whole response once all of its fragments
+    /// arrived.
+    pub fn push(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        if fragment.count == 0 || fragment.index >= fragment.count {
+            return None;
+        }
+        let partial = self.partial.entry(fragment.id).or_insert_with(|| Partial {
+            received: 0,
+            chunks: vec![None; fragment.count as usize],
+        });
+        // Drop fragments whose index does not fit the count of earlier ones.
+        let chunk = partial.chunks.get_mut(fragment.index as usize)?;
+        if chunk.is_none() {
+            *chunk = Some(fragment.data);
+            partial.received += 1;
+        }
+        if partial.received < partial.chunks.len() {
+            return None;
+        }
+        let partial = self.partial.remove(&fragment.id)?;
+        Some(partial.chunks.into_iter().flatten().flatten().collect())
+    }
+
+    /// Indices of the fragments of response `id` that did not arrive yet, or
+    /// `None` if no fragment of it arrived at all.
+    pub fn missing(&self, id: RequestId) -> Option<Vec<u32>> {
+        let partial = self.partial.get(&id)?;
+        Some(
+            partial
+                .chunks
+                .iter()
+                .enumerate()
+                .filter(|(_, chunk)| chunk.is_none())
+                .map(|(index, _)| index as u32)
+                .collect(),
+        )
+    }
+
+    /// Forgets the fragments of response `id`.
+    pub fn remove(&mut self, id: RequestId) {
+        self.partial.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encoded response of `len` bytes, covering every byte value.
+    fn response(codec: Codec, len: usize) -> Vec<u8> {
+        let mut bytes = match codec {
+            Codec::Json => vec![b'{'],
+            Codec::Binary => vec![crate::codec::BINARY_TAG],
+        };
+        bytes.extend((0..len - 1).map(|i| (i * 7) as u8));
+        bytes
+    }
+
+    #[test]
+    fn fragments_fit_their_size() {
+        for codec in [Codec::Json, Codec::Binary] {
+            let bytes = response(codec, 10_000);
+            let fragments = split(1, &bytes);
+            assert!(fragments.len() > 1);
+            for fragment in &fragments {
+                assert_eq!(fragment.count as usize, fragments.len());
+                let encoded = codec.encode(fragment).unwrap();
+                assert!(
+                    encoded.len() <= FRAGMENT_SIZE,
+                    "{:?} fragment of {} bytes",
+                    codec,
+                    encoded.len()
+                );
+            }
+            let joined: Vec<u8> = fragments.into_iter().flat_map(|x| x.data).collect();
+            assert_eq!(joined, bytes);
+        }
+    }
+
+    #[test]
+    fn empty_response_is_one_fragment() {
+        let fragments = split(1, &[]);
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments[0].count, 1);
+        assert!(fragments[0].data.is_empty());
+        assert_eq!(Reassembler::new().push(fragments[0].clone()), Some(vec![]));
+    }
+
+    #[test]
+    fn reassembles_out_of_order_and_duplicates() {
+        let bytes = response(Codec::Json, 3000);
+        let mut fragments = split(7, &bytes);
+        assert!(fragments.len() >= 3);
+        fragments.reverse();
+        let last = fragments.pop().unwrap();
+        let mut reassembler = Reassembler::new();
+        assert_eq!(reassembler.missing(7), None);
+        for fragment in &fragments {
+            assert_eq!(reassembler.push(fragment.clone()), None);
+            // Duplicates are ignored.
+            assert_eq!(reassembler.push(fragment.clone()), None);
+        }
+        assert_eq!(reassembler.missing(7), Some(vec![last.index]));
+        assert_eq!(reassembler.push(last), Some(bytes));
+        assert_eq!(reassembler.missing(7), None);
+    }
+
+    #[test]
+    fn missing_lists_every_absent_index() {
+        let fragments = split(3, &response(Codec::Binary, 5000));
+        let count = fragments.len() as u32;
+        let mut reassembler = Reassembler::new();
+        reassembler.push(fragments[1].clone());
+        let expected: Vec<u32> = (0..count).filter(|&i| i != 1).collect();
+        assert_eq!(reassembler.missing(3), Some(expected));
+        reassembler.remove(3);
+        assert_eq!(reassembler.missing(3), None);
+    }
+
+    #[test]
+    fn drops_inconsistent_fragments() {
+        let mut reassembler = Reassembler::new();
+        let fragment = |index, count| Fragment {
+            id: 1,
+            index,
+            count,
+            data: vec![1],
+        };
+        assert_eq!(reassembler.push(fragment(2, 2)), None);
+        assert_eq!(reassembler.push(fragment(0, 0)), None);
+        assert_eq!(reassembler.missing(1), None);
+        assert_eq!(reassembler.push(fragment(0, 2)), None);
+        // The count does not match the first fragment, the index is out of range.
+        assert_eq!(reassembler.push(fragment(2, 3)), None);
+        assert_eq!(reassembler.missing(1), Some(vec![1]));
+        assert_eq!(reassembler.push(fragment(1, 2)), Some(vec![1, 1]));
+    }
+}
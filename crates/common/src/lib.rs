@@ -1,18 +1,332 @@
+pub mod codec;
+pub mod fragment;
+pub mod retry;
+
+use codec::Codec;
 pub use serde;
 use serde::{Deserialize, Serialize};
 pub use serde_json;
-#[derive(Clone, Serialize, Deserialize)]
+/// Version of the protocol implemented by this crate. Bump it on every
+/// incompatible change of [`Instruction`] or [`Response`].
+pub const PROTOCOL_VERSION: u32 = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Instruction {
-    AddPhoneNumber { key: String, number: String },
-    DeleteUser { key: String },
-    EditNumber { key: String, number: String },
+    /// Handshake, must be the first instruction of every client. The server
+    /// answers with [`Response::Hello`] or refuses the client with
+    /// [`ErrorKind::ProtocolVersionMismatch`], or [`ErrorKind::Unauthorized`]
+    /// if the server requires an access token. Other instructions are
+    /// answered with [`ErrorKind::HandshakeRequired`] until it succeeded.
+    Hello {
+        protocol_version: u32,
+        client_name: String,
+        /// Codecs the client can encode, in order of preference.
+        #[serde(default)]
+        codecs: Vec<Codec>,
+        /// Access token, if the server is configured with one.
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Creates contact `key` with `number` as its primary number.
+    AddPhoneNumber {
+        key: String,
+        number: String,
+    },
+    DeleteUser {
+        key: String,
+    },
+    /// Replaces the primary number of contact `key`.
+    EditNumber {
+        key: String,
+        number: String,
+    },
+    /// Returns the primary number of contact `key`.
+    GetNumber {
+        key: String,
+    },
     GetAllUsers,
+    /// Returns up to `limit` contacts ordered by name, starting after `cursor`.
+    /// Pass `None` for the first page and the `next_cursor` of the previous
+    /// page for the following ones.
+    ListUsers {
+        cursor: Option<String>,
+        limit: u32,
+        /// Only lists members of this group.
+        #[serde(default)]
+        group: Option<String>,
+    },
+    /// Asks the server to send the given fragments of the response to
+    /// `request` again. Answered with the fragments themselves rather than with
+    /// a response of its own, unless the response is not known anymore.
+    ResendFragments {
+        request: RequestId,
+        indices: Vec<u32>,
+    },
+    /// Sets the number of contact `key`, creating the contact if it does not
+    /// exist. `AddPhoneNumber` refuses existing contacts instead.
+    UpsertNumber {
+        key: String,
+        number: String,
+    },
+    /// Adds another number to contact `key`, it becomes the primary one if the
+    /// contact has none. Answered with the updated [`Response::Contact`], like
+    /// the other instructions changing the numbers of a contact.
+    AddNumber {
+        key: String,
+        label: String,
+        number: String,
+    },
+    /// Removes `number` from contact `key`. Removing the primary number makes
+    /// the oldest remaining one primary.
+    RemoveNumber {
+        key: String,
+        number: String,
+    },
+    SetPrimaryNumber {
+        key: String,
+        number: String,
+    },
+    /// Replaces the details of contact `key`, answered with the updated
+    /// [`Response::Contact`]. Empty fields are cleared.
+    EditDetails {
+        key: String,
+        details: ContactDetails,
+    },
+    CreateGroup {
+        name: String,
+    },
+    /// Renames a group, its members stay in it.
+    RenameGroup {
+        name: String,
+        new_name: String,
+    },
+    /// Answered with [`Response::Groups`].
+    ListGroups,
+    /// Adds contact `key` to `group`, answered with the updated
+    /// [`Response::Contact`].
+    AssignGroup {
+        key: String,
+        group: String,
+    },
+    UnassignGroup {
+        key: String,
+        group: String,
+    },
+    /// Finds up to `limit` contacts whose `fields` contain `query`, answered
+    /// with [`Response::SearchResults`]. Numbers are compared by their digits
+    /// only, so any formatting of the query matches. Empty `fields` search all
+    /// of them.
+    Search {
+        query: String,
+        fields: Vec<SearchField>,
+        limit: u32,
+    },
+    /// Finds up to `limit` contacts with names similar to `query`, tolerating
+    /// typos, case and diacritics. Answered with [`Response::ScoredResults`].
+    FuzzySearch {
+        query: String,
+        limit: u32,
+    },
+    /// Creates contact `key` without any number, e.g. to restore an exported
+    /// contact whose numbers were all removed.
+    CreateUser {
+        key: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SearchField {
+    Name,
+    Number,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
-    Fail { message: String },
-    Number { number: String },
-    AllUsers(Vec<(String, String)>),
+    Fail {
+        kind: ErrorKind,
+        message: String,
+    },
+    Number {
+        /// Canonical E.164 form, e.g. `+14155550123`.
+        number: String,
+        /// Number as the user entered it.
+        display: String,
+    },
+    AllUsers(Vec<ContactInfo>),
+    Page {
+        contacts: Vec<ContactInfo>,
+        /// `None` once the last page was returned.
+        next_cursor: Option<String>,
+    },
     Success,
+    Hello {
+        /// Protocol versions the server supports.
+        versions: Vec<u32>,
+        capabilities: Vec<String>,
+        /// Codec picked for the session, clients encode further requests with it.
+        #[serde(default)]
+        codec: Codec,
+    },
+    Contact(ContactInfo),
+    /// Names of all groups, ordered by name.
+    Groups(Vec<String>),
+    /// Matches of `Instruction::Search`, those starting with the query first.
+    SearchResults(Vec<ContactInfo>),
+    /// Matches of `Instruction::FuzzySearch`, best first.
+    ScoredResults(Vec<ScoredContact>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredContact {
+    /// Relevance between 0 and 1, 1 meaning every word of the query matched.
+    pub score: f32,
+    pub contact: ContactInfo,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LabelledNumber {
+    /// What the number is for, e.g. `mobile`, `work` or `home`.
+    pub label: String,
+    /// Canonical E.164 form, identifies the number within its contact.
+    pub number: String,
+    /// Number as the user entered it.
+    pub display: String,
+    pub primary: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactInfo {
+    pub name: String,
+    /// Primary number first, the others in the order they were added.
+    pub numbers: Vec<LabelledNumber>,
+    pub details: ContactDetails,
+    /// Groups the contact belongs to, ordered by name.
+    pub groups: Vec<String>,
+}
+
+impl ContactInfo {
+    pub fn primary(&self) -> Option<&LabelledNumber> {
+        self.numbers.iter().find(|x| x.primary)
+    }
+}
+
+/// Longest values of [`ContactDetails`] fields, in characters.
+pub const MAX_EMAIL_LENGTH: usize = 254;
+pub const MAX_ORGANIZATION_LENGTH: usize = 200;
+pub const MAX_NOTES_LENGTH: usize = 4000;
+
+/// Longest group name, in characters.
+pub const MAX_GROUP_NAME_LENGTH: usize = 64;
+
+/// Checks a group name, which should be trimmed already.
+pub fn validate_group_name(name: &str) -> Result<(), String> {
+    if name.is_empty() {
+        Err("Group name is empty".to_owned())
+    } else if name.chars().count() > MAX_GROUP_NAME_LENGTH {
+        Err(format!(
+            "Group name is longer than {} characters",
+            MAX_GROUP_NAME_LENGTH
+        ))
+    } else if name.chars().any(char::is_control) {
+        Err("Group name contains control characters".to_owned())
+    } else {
+        Ok(())
+    }
+}
+
+/// Optional details of a contact besides its numbers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContactDetails {
+    pub email: Option<String>,
+    pub organization: Option<String>,
+    pub notes: Option<String>,
+}
+
+impl ContactDetails {
+    /// Trims every field and turns empty ones into `None`.
+    pub fn normalized(self) -> Self {
+        fn normalize(field: Option<String>) -> Option<String> {
+            field.map(|x| x.trim().to_owned()).filter(|x| !x.is_empty())
+        }
+        Self {
+            email: normalize(self.email),
+            organization: normalize(self.organization),
+            notes: normalize(self.notes),
+        }
+    }
+
+    /// Describes the first invalid field, if any.
+    pub fn validate(&self) -> Result<(), String> {
+        let fields = [
+            ("Email", &self.email, MAX_EMAIL_LENGTH),
+            ("Organization", &self.organization, MAX_ORGANIZATION_LENGTH),
+            ("Notes", &self.notes, MAX_NOTES_LENGTH),
+        ];
+        for (name, field, max) in fields {
+            if let Some(field) = field {
+                if field.chars().count() > max {
+                    return Err(format!("{} is longer than {} characters", name, max));
+                }
+            }
+        }
+        match &self.email {
+            Some(email) if !is_valid_email(email) => {
+                Err(format!("'{}' is not a valid email address", email))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks the shape `local@domain.tld`, which is as far as email syntax can be
+/// checked without sending mail.
+fn is_valid_email(email: &str) -> bool {
+    let (local, domain) = match email.split_once('@') {
+        Some(x) => x,
+        None => return false,
+    };
+    !local.is_empty()
+        && !domain.contains('@')
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !domain.contains("..")
+        && !email.chars().any(char::is_whitespace)
+}
+
+/// Reason of a [`Response::Fail`], lets clients react to failures without
+/// parsing the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorKind {
+    NotFound,
+    AlreadyExists,
+    InvalidNumber,
+    StorageError,
+    Unauthorized,
+    RateLimited,
+    ProtocolVersionMismatch,
+    /// A contact detail or group name failed validation, e.g. a malformed
+    /// email.
+    InvalidField,
+    /// The server has no session for the client, e.g. because it restarted
+    /// or evicted the session. The client has to send
+    /// [`Instruction::Hello`] again, then repeat the request.
+    HandshakeRequired,
+}
+
+pub type RequestId = u64;
+
+/// Every datagram is wrapped into an envelope. Client picks a fresh `id` for
+/// each request and the server echoes it back in the envelope of the response,
+/// that way the client can tell which request a response answers.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub id: RequestId,
+    pub body: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(id: RequestId, body: T) -> Self {
+        Self { id, body }
+    }
 }
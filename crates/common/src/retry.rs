@@ -0,0 +1,220 @@
+use crate::RequestId;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a response and how many times to resend a request
+/// before giving up on the server.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Time to wait for the response to the first attempt.
+    pub timeout: Duration,
+    /// Number of retransmissions after the first attempt.
+    pub retries: u32,
+    /// Every retransmission waits `backoff` times longer than the previous one.
+    pub backoff: u32,
+}
+
+impl RetryPolicy {
+    /// Time to wait for a response to the given attempt, attempts start at 0.
+    /// Saturates at [`Duration::MAX`] for huge timeouts.
+    pub fn timeout(&self, attempt: u32) -> Duration {
+        self.timeout
+            .checked_mul(self.backoff.saturating_pow(attempt))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            retries: 3,
+            backoff: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// No response arrived after all retransmissions.
+    Unreachable { attempts: u32 },
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unreachable { attempts } => write!(
+                f,
+                "Server unreachable: no response after {} attempts",
+                attempts
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+struct Entry<T> {
+    datagram: Vec<u8>,
+    attempt: u32,
+    /// `None` if the timeout is too long to be represented, the request then
+    /// never times out.
+    deadline: Option<Instant>,
+    tag: T,
+}
+
+/// Requests that were sent but not answered yet.
+///
+/// Keeps the encoded datagram of every request around so it can be resent
+/// when its response does not arrive in time. `T` is whatever the caller needs
+/// to handle the response later.
+pub struct Outstanding<T> {
+    policy: RetryPolicy,
+    entries: HashMap<RequestId, Entry<T>>,
+}
+
+impl<T> Outstanding<T> {
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, id: RequestId, datagram: Vec<u8>, tag: T) {
+        self.entries.insert(
+            id,
+            Entry {
+                datagram,
+                attempt: 0,
+                deadline: Instant::now().checked_add(self.policy.timeout(0)),
+                tag,
+            },
+        );
+    }
+
+    pub fn contains(&self, id: RequestId) -> bool {
+        self.entries.contains_key(&id)
+    }
+
+    /// Marks request `id` as answered.
+    pub fn remove(&mut self, id: RequestId) -> Option<T> {
+        self.entries.remove(&id).map(|entry| entry.tag)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Calls `resend` for every request whose timeout has expired at `now` and
+    /// that still has retransmissions left. Requests that ran out of them are
+    /// removed and returned together with their ids.
+    pub fn poll(
+        &mut self,
+        now: Instant,
+        mut resend: impl FnMut(RequestId, &[u8]),
+    ) -> Vec<(RequestId, T, RequestError)> {
+        let policy = self.policy;
+        let mut expired = vec![];
+        for (id, entry) in self.entries.iter_mut() {
+            if !matches!(entry.deadline, Some(deadline) if deadline <= now) {
+                continue;
+            }
+            if entry.attempt < policy.retries {
+                entry.attempt += 1;
+                entry.deadline = now.checked_add(policy.timeout(entry.attempt));
+                resend(*id, &entry.datagram);
+            } else {
+                expired.push(*id);
+            }
+        }
+        expired
+            .into_iter()
+            .filter_map(|id| self.entries.remove(&id).map(|entry| (id, entry)))
+            .map(|(id, entry)| {
+                (
+                    id,
+                    entry.tag,
+                    RequestError::Unreachable {
+                        attempts: entry.attempt + 1,
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outstanding(timeout: Duration, retries: u32) -> Outstanding<&'static str> {
+        Outstanding::new(RetryPolicy {
+            timeout,
+            retries,
+            backoff: 2,
+        })
+    }
+
+    #[test]
+    fn requests_are_resent_with_backoff_until_they_expire() {
+        let mut outstanding = outstanding(Duration::from_millis(100), 2);
+        outstanding.insert(1, vec![1], "request");
+        // The deadline of the first attempt is taken when inserting.
+        let start = Instant::now();
+        let mut resent = vec![];
+        let mut poll = |outstanding: &mut Outstanding<_>, after: u64| {
+            let now = start + Duration::from_millis(after);
+            outstanding.poll(now, |id, datagram| {
+                resent.push((id, datagram.to_vec(), after))
+            })
+        };
+
+        assert!(poll(&mut outstanding, 50).is_empty());
+        assert!(poll(&mut outstanding, 100).is_empty());
+        // The second attempt waits 200ms, the third 400ms.
+        assert!(poll(&mut outstanding, 250).is_empty());
+        assert!(poll(&mut outstanding, 300).is_empty());
+        assert!(poll(&mut outstanding, 650).is_empty());
+        assert!(poll(&mut outstanding, 699).is_empty());
+        let expired = poll(&mut outstanding, 700);
+        assert_eq!(
+            expired,
+            [(1, "request", RequestError::Unreachable { attempts: 3 })]
+        );
+        assert!(outstanding.is_empty());
+        assert_eq!(resent, [(1, vec![1], 100), (1, vec![1], 300)]);
+    }
+
+    #[test]
+    fn answered_requests_are_not_resent() {
+        let mut outstanding = outstanding(Duration::ZERO, 0);
+        outstanding.insert(1, vec![1], "answered");
+        outstanding.insert(2, vec![2], "lost");
+        assert_eq!(outstanding.remove(1), Some("answered"));
+        assert!(!outstanding.contains(1));
+        let expired = outstanding.poll(Instant::now(), |_, _| panic!("no retries left"));
+        assert_eq!(
+            expired,
+            [(2, "lost", RequestError::Unreachable { attempts: 1 })]
+        );
+    }
+
+    #[test]
+    fn huge_timeouts_do_not_overflow() {
+        let policy = RetryPolicy {
+            timeout: Duration::MAX,
+            retries: 3,
+            backoff: 2,
+        };
+        assert_eq!(policy.timeout(3), Duration::MAX);
+        let mut outstanding = Outstanding::new(policy);
+        outstanding.insert(1, vec![1], ());
+        let expired = outstanding.poll(Instant::now() + Duration::from_secs(3600), |_, _| {
+            panic!("not timed out yet")
+        });
+        assert!(expired.is_empty());
+        assert!(outstanding.contains(1));
+    }
+}
@@ -0,0 +1,412 @@
+mod output;
+
+use client_core::blocking::{unexpected, Connection, Error};
+use common::retry::RetryPolicy;
+use common::serde_json::{self, json, Value};
+use common::{ContactDetails, ContactInfo, ErrorKind, Instruction, Response};
+use output::Format;
+use std::io::{Read, Write};
+use std::time::Duration;
+
+const USAGE: &str = "\
+Usage: phonectl [options] <command> [arguments]
+
+Commands:
+    add <name> <number>           Add a contact
+    get <name>                    Print the primary number of a contact
+    edit <name> <number>          Change the primary number of a contact
+    delete <name>                 Delete a contact
+    list [--group <group>]        List contacts, ordered by name
+    search <query> [--fuzzy]      Find contacts by name or number, or by similar names
+    import <file>                 Add or update the contacts of a JSON file, - for stdin
+    export [--output <file>]      Write every contact as JSON, to stdout by default
+
+Options:
+    --server <addr:port>          Server to talk to, defaults to $PHONECTL_SERVER
+    --token <token>               Access token, defaults to $PHONECTL_TOKEN
+    --bind <addr:port>            Local address to send from (default: 0.0.0.0:0)
+    --format <table|json>         Output format (default: table)
+    --limit <n>                   Most contacts list and search print
+    --timeout <ms>                Time to wait for a response before resending (default: 500)
+    --retries <n>                 Times to resend a request before giving up (default: 3)
+    --help                        Print this message
+
+Exit codes:
+    0 success, 1 I/O error, 2 invalid arguments, 3 not found, 4 already exists,
+    5 invalid number, 6 invalid field, 7 unauthorized, 8 rate limited,
+    9 protocol version mismatch, 10 storage error, 11 server unreachable,
+    12 handshake required";
+
+/// Name phonectl introduces itself with in the handshake.
+const CLIENT_NAME: &str = concat!("phonectl ", env!("CARGO_PKG_VERSION"));
+
+const COMMANDS: &[&str] = &[
+    "add", "get", "edit", "delete", "list", "search", "import", "export",
+];
+
+const DEFAULT_BIND: &str = "0.0.0.0:0";
+
+/// Contacts requested per page, the largest page the server hands out.
+const PAGE_SIZE: u32 = 500;
+
+const EXIT_IO: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+/// Exit code for `error`, scripts can tell failures apart without parsing
+/// the message.
+fn exit_code(error: &Error) -> i32 {
+    match error {
+        Error::Io(_) => EXIT_IO,
+        Error::Fail { kind, .. } => match kind {
+            ErrorKind::NotFound => 3,
+            ErrorKind::AlreadyExists => 4,
+            ErrorKind::InvalidNumber => 5,
+            ErrorKind::InvalidField => 6,
+            ErrorKind::Unauthorized => 7,
+            ErrorKind::RateLimited => 8,
+            ErrorKind::ProtocolVersionMismatch => 9,
+            ErrorKind::StorageError => 10,
+            ErrorKind::HandshakeRequired => 12,
+        },
+        Error::Unreachable(_) => 11,
+    }
+}
+
+/// Kind of `error` in JSON output, null for local I/O failures.
+fn error_kind(error: &Error) -> Value {
+    match error {
+        Error::Fail { kind, .. } => json!(kind),
+        Error::Unreachable(_) => json!("Unreachable"),
+        Error::Io(_) => Value::Null,
+    }
+}
+
+struct Args {
+    server: Option<String>,
+    token: Option<String>,
+    bind: String,
+    policy: RetryPolicy,
+    format: Format,
+    limit: Option<u32>,
+    group: Option<String>,
+    output: Option<String>,
+    fuzzy: bool,
+    help: bool,
+    /// Command followed by its arguments.
+    command: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut parsed = Self {
+            server: std::env::var("PHONECTL_SERVER").ok(),
+            token: std::env::var("PHONECTL_TOKEN").ok(),
+            bind: DEFAULT_BIND.to_owned(),
+            policy: RetryPolicy::default(),
+            format: Format::Table,
+            limit: None,
+            group: None,
+            output: None,
+            fuzzy: false,
+            help: false,
+            command: vec![],
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{} expects a value", arg));
+            match arg.as_str() {
+                "--server" => parsed.server = Some(value()?),
+                "--token" => parsed.token = Some(value()?),
+                "--bind" => parsed.bind = value()?,
+                "--format" => parsed.format = value()?.parse()?,
+                "--limit" => {
+                    let limit = value()?;
+                    parsed.limit = Some(
+                        limit
+                            .parse()
+                            .map_err(|_| format!("Invalid limit '{}'", limit))?,
+                    );
+                }
+                "--timeout" => {
+                    let timeout = value()?;
+                    let ms = timeout
+                        .parse()
+                        .map_err(|_| format!("Invalid timeout '{}'", timeout))?;
+                    parsed.policy.timeout = Duration::from_millis(ms);
+                }
+                "--retries" => {
+                    let retries = value()?;
+                    parsed.policy.retries = retries
+                        .parse()
+                        .map_err(|_| format!("Invalid number of retries '{}'", retries))?;
+                }
+                "--group" => parsed.group = Some(value()?),
+                "--output" => parsed.output = Some(value()?),
+                "--fuzzy" => parsed.fuzzy = true,
+                "--help" | "-h" => parsed.help = true,
+                "-" => parsed.command.push(arg),
+                _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'", arg)),
+                _ => parsed.command.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => usage_error(&e),
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+    let format = args.format;
+    if let Err(e) = run(args) {
+        match format {
+            Format::Json => {
+                let error =
+                    json!({ "error": { "kind": error_kind(&e), "message": e.to_string() } });
+                eprintln!("{}", output::json(&error));
+            }
+            Format::Table => eprintln!("Error: {}", e),
+        }
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(EXIT_USAGE);
+}
+
+fn run(args: Args) -> Result<(), Error> {
+    let command: Vec<&str> = args.command.iter().map(String::as_str).collect();
+    // Check the arguments before waiting for the server.
+    match command.as_slice() {
+        ["add" | "edit", _, _]
+        | ["get" | "delete" | "search" | "import", _]
+        | ["list" | "export"] => {}
+        [] => usage_error("Missing command"),
+        [command, ..] if COMMANDS.contains(command) => {
+            usage_error(&format!("Invalid arguments of '{}'", command))
+        }
+        [command, ..] => usage_error(&format!("Unknown command '{}'", command)),
+    }
+    let server = match &args.server {
+        Some(server) => server,
+        None => usage_error("No server given, pass --server or set PHONECTL_SERVER"),
+    };
+    let mut connection = Connection::open(
+        &args.bind,
+        server,
+        args.policy,
+        CLIENT_NAME,
+        args.token.clone(),
+    )?;
+    let format = args.format;
+    match command.as_slice() {
+        ["add", name, number] => {
+            connection.request(Instruction::AddPhoneNumber {
+                key: name.to_string(),
+                number: number.to_string(),
+            })?;
+        }
+        ["edit", name, number] => {
+            connection.request(Instruction::EditNumber {
+                key: name.to_string(),
+                number: number.to_string(),
+            })?;
+        }
+        ["delete", name] => {
+            connection.request(Instruction::DeleteUser {
+                key: name.to_string(),
+            })?;
+        }
+        ["get", name] => {
+            let key = name.to_string();
+            match connection.request(Instruction::GetNumber { key: key.clone() })? {
+                Response::Number { number, display } => match format {
+                    Format::Json => println!(
+                        "{}",
+                        output::json(&json!({ "name": key, "number": number, "display": display }))
+                    ),
+                    Format::Table => println!(
+                        "{}",
+                        output::table(&["NAME", "NUMBER", "E.164"], &[vec![key, display, number]])
+                    ),
+                },
+                response => return Err(unexpected(response)),
+            }
+        }
+        ["list"] => {
+            let contacts = list(&mut connection, args.group.clone(), args.limit)?;
+            println!("{}", output::contacts(format, &contacts));
+        }
+        ["search", query] => {
+            let limit = args.limit.unwrap_or(20);
+            let query = query.to_string();
+            if args.fuzzy {
+                match connection.request(Instruction::FuzzySearch { query, limit })? {
+                    Response::ScoredResults(results) => {
+                        println!("{}", output::scored_contacts(format, &results))
+                    }
+                    response => return Err(unexpected(response)),
+                }
+            } else {
+                let fields = vec![];
+                match connection.request(Instruction::Search {
+                    query,
+                    fields,
+                    limit,
+                })? {
+                    Response::SearchResults(results) => {
+                        println!("{}", output::contacts(format, &results))
+                    }
+                    response => return Err(unexpected(response)),
+                }
+            }
+        }
+        ["export"] => {
+            let contacts = list(&mut connection, args.group.clone(), None)?;
+            let json = output::json(&contacts);
+            match &args.output {
+                Some(path) => std::fs::write(path, json + "\n")?,
+                None => println!("{}", json),
+            }
+        }
+        ["import", path] => import(&mut connection, path, format)?,
+        _ => unreachable!("arguments are checked above"),
+    }
+    Ok(())
+}
+
+/// Fetches contacts page by page, up to `limit` of them.
+fn list(
+    connection: &mut Connection,
+    group: Option<String>,
+    limit: Option<u32>,
+) -> Result<Vec<ContactInfo>, Error> {
+    let mut contacts = vec![];
+    let mut cursor = None;
+    loop {
+        let remaining = limit.map_or(PAGE_SIZE, |limit| limit - contacts.len() as u32);
+        if remaining == 0 {
+            return Ok(contacts);
+        }
+        match connection.request(Instruction::ListUsers {
+            cursor,
+            limit: remaining.min(PAGE_SIZE),
+            group: group.clone(),
+        })? {
+            Response::Page {
+                contacts: page,
+                next_cursor,
+            } => {
+                contacts.extend(page);
+                match next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => return Ok(contacts),
+                }
+            }
+            response => return Err(unexpected(response)),
+        }
+    }
+}
+
+/// Adds or updates every contact of the JSON file at `path`, as written by
+/// `export`. Contacts that fail are reported and skipped, the error of the
+/// first one is returned once all were tried.
+fn import(connection: &mut Connection, path: &str, format: Format) -> Result<(), Error> {
+    let mut text = String::new();
+    if path == "-" {
+        std::io::stdin().read_to_string(&mut text)?;
+    } else {
+        text = std::fs::read_to_string(path)?;
+    }
+    let contacts: Vec<ContactInfo> = serde_json::from_str(&text)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    let total = contacts.len();
+    let mut failed = vec![];
+    let mut first_error = None;
+    for contact in contacts {
+        let name = contact.name.clone();
+        if let Err(e) = import_contact(connection, contact) {
+            if format == Format::Table {
+                eprintln!("Failed to import {}: {}", name, e);
+            }
+            failed.push(json!({ "name": name, "kind": error_kind(&e), "message": e.to_string() }));
+            first_error.get_or_insert(e);
+        }
+    }
+    let imported = total - failed.len();
+    match format {
+        Format::Json => println!(
+            "{}",
+            output::json(&json!({ "imported": imported, "failed": failed }))
+        ),
+        Format::Table => println!("Imported {} of {} contacts", imported, total),
+    }
+    std::io::stdout().flush()?;
+    first_error.map_or(Ok(()), Err)
+}
+
+fn import_contact(connection: &mut Connection, contact: ContactInfo) -> Result<(), Error> {
+    let key = contact.name;
+    let primary = contact
+        .numbers
+        .iter()
+        .find(|x| x.primary)
+        .or_else(|| contact.numbers.first());
+    // Importing the same file twice must not fail on what is there already.
+    match primary {
+        // Numbers are sent as displayed, so they keep their formatting.
+        Some(primary) => {
+            connection.request(Instruction::UpsertNumber {
+                key: key.clone(),
+                number: primary.display.clone(),
+            })?;
+        }
+        None => ignore_existing(connection.request(Instruction::CreateUser { key: key.clone() }))?,
+    }
+    for number in contact
+        .numbers
+        .iter()
+        .filter(|x| !primary.is_some_and(|primary| std::ptr::eq(*x, primary)))
+    {
+        ignore_existing(connection.request(Instruction::AddNumber {
+            key: key.clone(),
+            label: number.label.clone(),
+            number: number.display.clone(),
+        }))?;
+    }
+    if contact.details != ContactDetails::default() {
+        connection.request(Instruction::EditDetails {
+            key: key.clone(),
+            details: contact.details,
+        })?;
+    }
+    for group in contact.groups {
+        ignore_existing(connection.request(Instruction::CreateGroup {
+            name: group.clone(),
+        }))?;
+        connection.request(Instruction::AssignGroup {
+            key: key.clone(),
+            group,
+        })?;
+    }
+    Ok(())
+}
+
+fn ignore_existing(result: Result<Response, Error>) -> Result<(), Error> {
+    match result {
+        Ok(_)
+        | Err(Error::Fail {
+            kind: ErrorKind::AlreadyExists,
+            ..
+        }) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,108 @@
+use common::serde::Serialize;
+use common::serde_json;
+use common::{ContactInfo, ScoredContact};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("Unknown format '{}', expected table or json", s)),
+        }
+    }
+}
+
+pub fn json<T: Serialize + ?Sized>(value: &T) -> String {
+    // Our types only have string keys, serializing them cannot fail.
+    serde_json::to_string_pretty(value).unwrap()
+}
+
+/// Formats `rows` as columns aligned under `headers`.
+pub fn table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|x| x.chars().count()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let headers: Vec<String> = headers.iter().map(|x| x.to_string()).collect();
+    std::iter::once(&headers)
+        .chain(rows)
+        .map(|row| {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect();
+            line.join("  ").trim_end().to_owned()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn contacts(format: Format, contacts: &[ContactInfo]) -> String {
+    match format {
+        Format::Json => json(contacts),
+        Format::Table => {
+            let rows: Vec<Vec<String>> = contacts.iter().map(contact_row).collect();
+            table(&CONTACT_HEADERS, &rows)
+        }
+    }
+}
+
+pub fn scored_contacts(format: Format, contacts: &[ScoredContact]) -> String {
+    match format {
+        Format::Json => json(contacts),
+        Format::Table => {
+            let headers: Vec<&str> = std::iter::once("SCORE")
+                .chain(CONTACT_HEADERS.iter().copied())
+                .collect();
+            let rows: Vec<Vec<String>> = contacts
+                .iter()
+                .map(|x| {
+                    std::iter::once(format!("{:.2}", x.score))
+                        .chain(contact_row(&x.contact))
+                        .collect()
+                })
+                .collect();
+            table(&headers, &rows)
+        }
+    }
+}
+
+const CONTACT_HEADERS: [&str; 6] = [
+    "NAME",
+    "NUMBER",
+    "OTHER NUMBERS",
+    "EMAIL",
+    "ORGANIZATION",
+    "GROUPS",
+];
+
+fn contact_row(contact: &ContactInfo) -> Vec<String> {
+    let others: Vec<String> = contact
+        .numbers
+        .iter()
+        .filter(|x| !x.primary)
+        .map(|x| format!("{}: {}", x.label, x.display))
+        .collect();
+    vec![
+        contact.name.clone(),
+        contact
+            .primary()
+            .map(|x| x.display.clone())
+            .unwrap_or_default(),
+        others.join(", "),
+        contact.details.email.clone().unwrap_or_default(),
+        contact.details.organization.clone().unwrap_or_default(),
+        contact.groups.join(", "),
+    ]
+}
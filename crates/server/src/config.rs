@@ -0,0 +1,356 @@
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+
+/// Config file read when neither `--config` nor [`CONFIG_VARIABLE`] name one.
+pub const DEFAULT_CONFIG_PATH: &str = "server.toml";
+
+/// Environment variable naming the config file.
+pub const CONFIG_VARIABLE: &str = "PHONEBOOK_CONFIG";
+
+pub const USAGE: &str = "\
+Usage: server [options]
+
+Options:
+    --config <path>               Read settings from a TOML file (default: server.toml if present)
+    --bind <addr:port>            Address to listen on, asked for on stdin if not set
+    --db <path>                   Database file (default: users.db)
+    --ephemeral                   Keep the database in memory, nothing is written to disk
+    --log-level <level>           off, error, warn, info, debug or trace (default: info)
+    --max-sessions <n>            Clients remembered at once (default: 1024)
+    --dedup-responses <n>         Responses kept per client for retransmissions (default: 64)
+    --dedup-bytes <n>             Total size of the kept responses in bytes (default: 67108864)
+    --requests-per-second <n>     Requests accepted per client and second, 0 for no limit (default: 0)
+    --token <token>               Access token clients must send in their handshake
+    --allow <ip,...>              Only serve these addresses (default: everybody)
+    --help                        Print this message
+
+Every setting can also be given in the environment, e.g. PHONEBOOK_BIND or
+PHONEBOOK_MAX_SESSIONS. Command line arguments override the environment, which
+overrides the config file.";
+
+/// Settings that can be given on the command line and in the environment,
+/// with the key naming them in [`Config::set`].
+const FLAGS: &[(&str, &str, &str)] = &[
+    ("--bind", "PHONEBOOK_BIND", "bind"),
+    ("--db", "PHONEBOOK_DB", "db"),
+    ("--log-level", "PHONEBOOK_LOG_LEVEL", "log_level"),
+    (
+        "--max-sessions",
+        "PHONEBOOK_MAX_SESSIONS",
+        "limits.max_sessions",
+    ),
+    (
+        "--dedup-responses",
+        "PHONEBOOK_DEDUP_RESPONSES",
+        "limits.dedup_responses_per_client",
+    ),
+    (
+        "--dedup-bytes",
+        "PHONEBOOK_DEDUP_BYTES",
+        "limits.dedup_bytes",
+    ),
+    (
+        "--requests-per-second",
+        "PHONEBOOK_REQUESTS_PER_SECOND",
+        "limits.requests_per_second",
+    ),
+    ("--token", "PHONEBOOK_TOKEN", "auth.token"),
+    (
+        "--allow",
+        "PHONEBOOK_ALLOWED_ADDRESSES",
+        "auth.allowed_addresses",
+    ),
+];
+
+/// Settings of the server, see `server.example.toml` for their meaning.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address to listen on, asked for on stdin if not configured.
+    pub bind: Option<String>,
+    pub db: String,
+    pub log_level: LevelFilter,
+    pub limits: Limits,
+    pub auth: Auth,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Clients with a session and cached responses at once, the least
+    /// recently seen ones are forgotten first.
+    pub max_sessions: usize,
+    /// Responses kept per client for answering retransmitted requests.
+    pub dedup_responses_per_client: usize,
+    /// Total size in bytes of the responses kept for all clients.
+    pub dedup_bytes: usize,
+    /// Requests accepted per client and second, 0 disables the limit.
+    pub requests_per_second: u32,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    /// Token clients must send in their `Hello`, anybody may connect if unset.
+    pub token: Option<String>,
+    /// Addresses allowed to talk to the server, all of them if empty.
+    pub allowed_addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    /// Value of a command line argument or environment variable is malformed.
+    Invalid(String, String),
+    MissingValue(String),
+    UnknownArgument(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, e) => write!(f, "Failed to read config file '{}': {}", path, e),
+            Self::Parse(path, e) => write!(f, "Invalid config file '{}': {}", path, e),
+            Self::Invalid(name, message) => write!(f, "Invalid value of {}: {}", name, message),
+            Self::MissingValue(flag) => write!(f, "{} expects a value", flag),
+            Self::UnknownArgument(arg) => write!(f, "Unknown argument '{}'", arg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            db: "users.db".to_owned(),
+            log_level: LevelFilter::Info,
+            limits: Limits::default(),
+            auth: Auth::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_sessions: 1024,
+            dedup_responses_per_client: 64,
+            dedup_bytes: 64 << 20,
+            requests_per_second: 0,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the defaults, overridden by the config
+    /// file, then by the environment, then by the command line `args`.
+    /// Returns `None` if `args` ask for the usage instead.
+    pub fn load(args: impl IntoIterator<Item = String>) -> Result<Option<Self>, ConfigError> {
+        Self::load_with(args, |variable| std::env::var(variable).ok())
+    }
+
+    /// [`Config::load`] with the environment variables looked up by `env`.
+    fn load_with(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Option<Self>, ConfigError> {
+        let mut path = None;
+        let mut overrides = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--help" | "-h" => return Ok(None),
+                "--ephemeral" => overrides.push(("--ephemeral", "db", ":memory:".to_owned())),
+                "--config" => match args.next() {
+                    Some(value) => path = Some(value),
+                    None => return Err(ConfigError::MissingValue(arg)),
+                },
+                _ => match FLAGS.iter().find(|(flag, ..)| *flag == arg) {
+                    Some((flag, _, key)) => match args.next() {
+                        Some(value) => overrides.push((*flag, *key, value)),
+                        None => return Err(ConfigError::MissingValue(arg)),
+                    },
+                    None => return Err(ConfigError::UnknownArgument(arg)),
+                },
+            }
+        }
+
+        // An explicitly named config file must exist, the default one is optional.
+        let path = path.or_else(|| env(CONFIG_VARIABLE));
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Self::default(),
+        };
+        for (_, variable, key) in FLAGS {
+            if let Some(value) = env(variable) {
+                config
+                    .set(key, &value)
+                    .map_err(|e| ConfigError::Invalid(variable.to_string(), e))?;
+            }
+        }
+        for (flag, key, value) in overrides {
+            config
+                .set(key, &value)
+                .map_err(|e| ConfigError::Invalid(flag.to_owned(), e))?;
+        }
+        Ok(Some(config))
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let text =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    /// Sets the setting `key`, named like in the config file, to `value`.
+    /// Lists are separated by commas, an empty value unsets optional settings.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let optional = || Some(value.to_owned()).filter(|value| !value.is_empty());
+        match key {
+            "bind" => self.bind = optional(),
+            "db" => self.db = value.to_owned(),
+            "log_level" => self.log_level = parse(value)?,
+            "limits.max_sessions" => self.limits.max_sessions = parse(value)?,
+            "limits.dedup_responses_per_client" => {
+                self.limits.dedup_responses_per_client = parse(value)?
+            }
+            "limits.dedup_bytes" => self.limits.dedup_bytes = parse(value)?,
+            "limits.requests_per_second" => self.limits.requests_per_second = parse(value)?,
+            "auth.token" => self.auth.token = optional(),
+            "auth.allowed_addresses" => {
+                self.auth.allowed_addresses = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|address| !address.is_empty())
+                    .map(parse)
+                    .collect::<Result<_, _>>()?
+            }
+            _ => unreachable!("unknown setting {}", key),
+        }
+        Ok(())
+    }
+
+    /// Whether `address` may talk to the server.
+    pub fn is_allowed(&self, address: IpAddr) -> bool {
+        self.auth.allowed_addresses.is_empty() || self.auth.allowed_addresses.contains(&address)
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value.parse().map_err(|e| format!("'{}' ({})", value, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Tells apart the config files of tests running in parallel.
+    static FILES: AtomicUsize = AtomicUsize::new(0);
+
+    /// Loads the configuration from `args`, with `env` as the environment
+    /// and `file` as the config file.
+    fn load(args: &[&str], env: &[(&str, &str)], file: &str) -> Result<Config, ConfigError> {
+        let path = std::env::temp_dir().join(format!(
+            "phonebook-config-{}-{}.toml",
+            std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&path, file).unwrap();
+        let mut env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        env.insert(CONFIG_VARIABLE.to_owned(), path.display().to_string());
+        let args = args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let config = Config::load_with(args, |variable| env.get(variable).cloned());
+        let _ = std::fs::remove_file(&path);
+        config.map(|config| config.expect("no usage asked for"))
+    }
+
+    #[test]
+    fn command_line_overrides_environment_overrides_file() {
+        let file = r#"
+            bind = "0.0.0.0:1000"
+            db = "file.db"
+            log_level = "warn"
+
+            [limits]
+            max_sessions = 10
+        "#;
+        let env = [("PHONEBOOK_DB", "env.db"), ("PHONEBOOK_LOG_LEVEL", "error")];
+        let config = load(&["--db", "args.db"], &env, file).unwrap();
+        assert_eq!(config.db, "args.db");
+        assert_eq!(config.log_level, LevelFilter::Error);
+        assert_eq!(config.bind.as_deref(), Some("0.0.0.0:1000"));
+        assert_eq!(config.limits.max_sessions, 10);
+        assert_eq!(config.limits.dedup_responses_per_client, 64);
+    }
+
+    #[test]
+    fn lists_are_split_and_empty_values_unset() {
+        let file = r#"
+            [auth]
+            token = "secret"
+        "#;
+        let env = [("PHONEBOOK_TOKEN", "")];
+        let config = load(&["--allow", "127.0.0.1, ::1,"], &env, file).unwrap();
+        assert_eq!(config.auth.token, None);
+        assert_eq!(
+            config.auth.allowed_addresses,
+            [
+                "127.0.0.1".parse::<IpAddr>().unwrap(),
+                "::1".parse().unwrap()
+            ]
+        );
+        assert!(config.is_allowed("::1".parse().unwrap()));
+        assert!(!config.is_allowed("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn malformed_values_are_reported() {
+        let env = [("PHONEBOOK_MAX_SESSIONS", "many")];
+        match load(&[], &env, "") {
+            Err(ConfigError::Invalid(name, _)) => assert_eq!(name, "PHONEBOOK_MAX_SESSIONS"),
+            result => panic!("expected an invalid value, got {:?}", result),
+        }
+        assert!(matches!(
+            load(&["--allow", "localhost"], &[], ""),
+            Err(ConfigError::Invalid(..))
+        ));
+        assert!(matches!(
+            load(&["--db"], &[], ""),
+            Err(ConfigError::MissingValue(_))
+        ));
+        assert!(matches!(
+            load(&["--verbose"], &[], ""),
+            Err(ConfigError::UnknownArgument(_))
+        ));
+        assert!(matches!(
+            load(&[], &[], "unknown = 1"),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+
+    #[test]
+    fn help_is_only_an_option() {
+        let config = load(&["--token", "-h"], &[], "").unwrap();
+        assert_eq!(config.auth.token.as_deref(), Some("-h"));
+        let args = ["--db", "x.db", "-h"].map(str::to_owned);
+        assert!(Config::load_with(args, |_| None).unwrap().is_none());
+    }
+}
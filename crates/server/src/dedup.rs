@@ -0,0 +1,144 @@
+use common::RequestId;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+struct Client {
+    last_seen: u64,
+    responses: VecDeque<(RequestId, Vec<u8>)>,
+    /// Total size of `responses`.
+    bytes: usize,
+}
+
+/// Remembers the responses to the latest requests of every client.
+///
+/// Clients retransmit requests whose response got lost, so the same request
+/// may arrive several times. A request found in the cache is answered with
+/// the stored response instead of being executed again.
+///
+/// The number of clients, the number of responses per client and the total
+/// size of the responses are bounded. The least recently seen client and the
+/// oldest responses are evicted first, responses over the size budget are
+/// taken from the client holding the most bytes.
+pub struct DedupCache {
+    max_clients: usize,
+    per_client: usize,
+    max_bytes: usize,
+    bytes: usize,
+    clock: u64,
+    clients: HashMap<SocketAddr, Client>,
+}
+
+impl DedupCache {
+    pub fn new(max_clients: usize, per_client: usize, max_bytes: usize) -> Self {
+        Self {
+            max_clients,
+            per_client,
+            max_bytes,
+            bytes: 0,
+            clock: 0,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Returns the response sent to request `id` of `addr`, if it is still cached.
+    pub fn get(&self, addr: SocketAddr, id: RequestId) -> Option<&[u8]> {
+        let client = self.clients.get(&addr)?;
+        client
+            .responses
+            .iter()
+            .find(|(x, _)| *x == id)
+            .map(|(_, response)| response.as_slice())
+    }
+
+    /// Stores `response`, unless it alone exceeds the size budget.
+    pub fn insert(&mut self, addr: SocketAddr, id: RequestId, response: Vec<u8>) {
+        if self.max_clients == 0 || self.per_client == 0 || response.len() > self.max_bytes {
+            return;
+        }
+        self.clock += 1;
+        if !self.clients.contains_key(&addr) && self.clients.len() >= self.max_clients {
+            let oldest = self
+                .clients
+                .iter()
+                .min_by_key(|(_, client)| client.last_seen)
+                .map(|(addr, _)| *addr);
+            if let Some(client) = oldest.and_then(|oldest| self.clients.remove(&oldest)) {
+                self.bytes -= client.bytes;
+            }
+        }
+        let client = self.clients.entry(addr).or_insert_with(|| Client {
+            last_seen: 0,
+            responses: VecDeque::new(),
+            bytes: 0,
+        });
+        client.last_seen = self.clock;
+        if client.responses.len() >= self.per_client {
+            if let Some((_, evicted)) = client.responses.pop_front() {
+                client.bytes -= evicted.len();
+                self.bytes -= evicted.len();
+            }
+        }
+        client.bytes += response.len();
+        self.bytes += response.len();
+        client.responses.push_back((id, response));
+        while self.bytes > self.max_bytes {
+            self.evict_largest();
+        }
+    }
+
+    /// Drops the oldest response of the client holding the most bytes.
+    fn evict_largest(&mut self) {
+        let largest = self
+            .clients
+            .iter()
+            .max_by_key(|(_, client)| client.bytes)
+            .map(|(addr, _)| *addr);
+        let largest = match largest {
+            Some(addr) => addr,
+            None => return,
+        };
+        let client = self.clients.get_mut(&largest).expect("found above");
+        if let Some((_, evicted)) = client.responses.pop_front() {
+            client.bytes -= evicted.len();
+            self.bytes -= evicted.len();
+        }
+        if client.responses.is_empty() {
+            self.clients.remove(&largest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn evicts_the_largest_client_over_budget() {
+        let mut cache = DedupCache::new(16, 16, 100);
+        cache.insert(addr(1), 1, vec![0; 30]);
+        cache.insert(addr(2), 1, vec![0; 40]);
+        cache.insert(addr(2), 2, vec![0; 20]);
+        // 30 + 40 + 20 + 20 is over the budget, the oldest response of the
+        // client with 60 bytes goes.
+        cache.insert(addr(1), 2, vec![0; 20]);
+        assert!(cache.get(addr(1), 1).is_some());
+        assert!(cache.get(addr(1), 2).is_some());
+        assert!(cache.get(addr(2), 1).is_none());
+        assert!(cache.get(addr(2), 2).is_some());
+        assert_eq!(cache.bytes, 70);
+    }
+
+    #[test]
+    fn skips_responses_over_budget() {
+        let mut cache = DedupCache::new(16, 16, 100);
+        cache.insert(addr(1), 1, vec![0; 50]);
+        cache.insert(addr(1), 2, vec![0; 101]);
+        assert!(cache.get(addr(1), 1).is_some());
+        assert!(cache.get(addr(1), 2).is_none());
+        assert_eq!(cache.bytes, 50);
+    }
+}
@@ -0,0 +1,847 @@
+use crate::search::SearchIndex;
+use common::validate_group_name;
+use common::ContactDetails;
+use common::ContactInfo;
+use common::ErrorKind;
+use common::Instruction;
+use common::LabelledNumber;
+use common::Response;
+use common::ScoredContact;
+use common::SearchField;
+use log::{error, info};
+use phonenumber::Mode;
+use sqlite::Connection;
+
+/// Primary result code sqlite reports for violated constraints.
+const SQLITE_CONSTRAINT: isize = 19;
+
+/// Label of numbers added without one, e.g. by `Instruction::AddPhoneNumber`.
+const DEFAULT_LABEL: &str = "mobile";
+
+/// Largest page `Instruction::ListUsers` returns, bigger limits are clamped.
+pub const MAX_PAGE_SIZE: u32 = 500;
+
+/// Most results `Instruction::Search` returns, bigger limits are clamped.
+pub const MAX_SEARCH_RESULTS: u32 = 100;
+
+/// Executes `instruction` against the database. Every instruction produces
+/// exactly one response, mutations answer with [`Response::Success`] once the
+/// change is stored, or with the updated [`Response::Contact`] if they change
+/// the numbers, details or groups of a contact. `index` is kept in sync with
+/// the contacts that are added and deleted.
+pub fn execute(db: &Connection, index: &mut SearchIndex, instruction: Instruction) -> Response {
+    let (result, action) = match instruction {
+        Instruction::AddPhoneNumber { key, number } => {
+            info!("AddPhoneNumber: {} {}", key, number);
+            let result = add_user(db, &key, Some(&number));
+            if let Ok(Response::Success) = result {
+                index.insert(&key);
+            }
+            (result, "adding user entry")
+        }
+        Instruction::CreateUser { key } => {
+            info!("Create user {}", key);
+            let result = add_user(db, &key, None);
+            if let Ok(Response::Success) = result {
+                index.insert(&key);
+            }
+            (result, "adding user entry")
+        }
+        Instruction::EditNumber { key, number } => {
+            info!("Edit number: {} {}", key, number);
+            (edit_number(db, &key, &number), "editing user entry")
+        }
+        Instruction::DeleteUser { key } => {
+            info!("Delete user {}", key);
+            let result = delete_user(db, &key);
+            if let Ok(Response::Success) = result {
+                index.remove(&key);
+            }
+            (result, "deleting user entry")
+        }
+        Instruction::UpsertNumber { key, number } => {
+            info!("Upsert number: {} {}", key, number);
+            let result = upsert_number(db, &key, &number);
+            if let Ok(Response::Success) = result {
+                index.insert(&key);
+            }
+            (result, "upserting user entry")
+        }
+        Instruction::AddNumber { key, label, number } => {
+            info!("Add {} number of {}: {}", label, key, number);
+            (add_number(db, &key, &label, &number), "adding number")
+        }
+        Instruction::RemoveNumber { key, number } => {
+            info!("Remove number of {}: {}", key, number);
+            (remove_number(db, &key, &number), "removing number")
+        }
+        Instruction::SetPrimaryNumber { key, number } => {
+            info!("Set primary number of {}: {}", key, number);
+            (set_primary(db, &key, &number), "setting primary number")
+        }
+        Instruction::EditDetails { key, details } => {
+            info!("Edit details of {}", key);
+            (edit_details(db, &key, details), "editing user details")
+        }
+        Instruction::Search {
+            query,
+            fields,
+            limit,
+        } => {
+            let limit = limit.clamp(1, MAX_SEARCH_RESULTS);
+            info!("Search for {:?} in {:?}", query, fields);
+            (search(db, &query, &fields, limit), "searching users")
+        }
+        Instruction::FuzzySearch { query, limit } => {
+            let limit = limit.clamp(1, MAX_SEARCH_RESULTS);
+            info!("Fuzzy search for {:?}", query);
+            (fuzzy_search(db, index, &query, limit), "searching users")
+        }
+        Instruction::GetNumber { key } => {
+            info!("Get number of {}", key);
+            (get_number(db, &key), "receiving user number")
+        }
+        Instruction::ListUsers {
+            cursor,
+            limit,
+            group,
+        } => {
+            let limit = limit.clamp(1, MAX_PAGE_SIZE);
+            info!(
+                "Listing {} users after {:?} in group {:?}",
+                limit, cursor, group
+            );
+            (list_users(db, cursor, limit, group), "listing users")
+        }
+        Instruction::CreateGroup { name } => {
+            info!("Create group {}", name);
+            (create_group(db, &name), "creating group")
+        }
+        Instruction::RenameGroup { name, new_name } => {
+            info!("Rename group {} to {}", name, new_name);
+            (rename_group(db, &name, &new_name), "renaming group")
+        }
+        Instruction::ListGroups => {
+            info!("Listing groups");
+            (list_groups(db), "listing groups")
+        }
+        Instruction::AssignGroup { key, group } => {
+            info!("Assign {} to group {}", key, group);
+            (assign_group(db, &key, &group, true), "assigning group")
+        }
+        Instruction::UnassignGroup { key, group } => {
+            info!("Unassign {} from group {}", key, group);
+            (assign_group(db, &key, &group, false), "unassigning group")
+        }
+        Instruction::GetAllUsers => {
+            info!("Fetching users...");
+            (all_users(db), "fetching users")
+        }
+        // Answered by the session layer before instructions reach the handler.
+        Instruction::Hello { .. } => {
+            return Response::Fail {
+                kind: ErrorKind::ProtocolVersionMismatch,
+                message: "Unexpected handshake".to_owned(),
+            }
+        }
+        Instruction::ResendFragments { request, .. } => {
+            return Response::Fail {
+                kind: ErrorKind::NotFound,
+                message: format!("Response to request {} is not available anymore", request),
+            }
+        }
+    };
+    result.unwrap_or_else(|e| {
+        error!("Sqlite error: {}", e);
+        Response::Fail {
+            kind: match e.code {
+                Some(SQLITE_CONSTRAINT) => ErrorKind::AlreadyExists,
+                _ => ErrorKind::StorageError,
+            },
+            message: format!("Sqlite failure on {}: {}", action, e),
+        }
+    })
+}
+
+/// Creates contact `key`, with `number` as its primary number if given.
+fn add_user(db: &Connection, key: &str, number: Option<&str>) -> sqlite::Result<Response> {
+    let number = match number {
+        Some(number) => match canonical_number(number) {
+            Some(canonical) => Some((canonical, number)),
+            None => return Ok(invalid_number(number)),
+        },
+        None => None,
+    };
+    transaction(db, || {
+        let mut statement = db.prepare(
+            "INSERT INTO users (name, created_at, updated_at) \
+            VALUES (:key, strftime('%s', 'now'), strftime('%s', 'now'))",
+        )?;
+        statement.bind_by_name(":key", key)?;
+        match statement.next() {
+            Ok(_) => {}
+            // The unique index on names refuses a second contact with the same name.
+            Err(e) if e.code == Some(SQLITE_CONSTRAINT) => {
+                return Ok(Response::Fail {
+                    kind: ErrorKind::AlreadyExists,
+                    message: format!("User '{}' already exists", key),
+                })
+            }
+            Err(e) => return Err(e),
+        }
+        if let Some((canonical, display)) = &number {
+            set_primary_number(db, key, canonical, display)?;
+        }
+        Ok(Response::Success)
+    })
+}
+
+fn edit_number(db: &Connection, key: &str, number: &str) -> sqlite::Result<Response> {
+    let canonical = match canonical_number(number) {
+        Some(x) => x,
+        None => return Ok(invalid_number(number)),
+    };
+    transaction(db, || {
+        if !touch(db, key)? {
+            return Ok(not_found(key));
+        }
+        set_primary_number(db, key, &canonical, number)?;
+        Ok(Response::Success)
+    })
+}
+
+fn upsert_number(db: &Connection, key: &str, number: &str) -> sqlite::Result<Response> {
+    let canonical = match canonical_number(number) {
+        Some(x) => x,
+        None => return Ok(invalid_number(number)),
+    };
+    transaction(db, || {
+        let mut statement = db.prepare(
+            "INSERT INTO users (name, created_at, updated_at) \
+            VALUES (:key, strftime('%s', 'now'), strftime('%s', 'now')) \
+            ON CONFLICT (name) DO UPDATE SET updated_at = excluded.updated_at",
+        )?;
+        statement.bind_by_name(":key", key)?;
+        statement.next()?;
+        set_primary_number(db, key, &canonical, number)?;
+        Ok(Response::Success)
+    })
+}
+
+/// Replaces the primary number of `key`, or adds it as the primary one if the
+/// contact has none.
+fn set_primary_number(
+    db: &Connection,
+    key: &str,
+    canonical: &str,
+    display: &str,
+) -> sqlite::Result<()> {
+    let mut statement = db.prepare(
+        "UPDATE numbers SET number = :number, display_number = :display \
+        WHERE user = :key AND is_primary = 1",
+    )?;
+    statement.bind_by_name(":key", key)?;
+    statement.bind_by_name(":number", canonical)?;
+    statement.bind_by_name(":display", display)?;
+    statement.next()?;
+    if db.change_count() == 0 {
+        insert_number(db, key, DEFAULT_LABEL, canonical, display, true)?;
+    }
+    Ok(())
+}
+
+fn add_number(db: &Connection, key: &str, label: &str, number: &str) -> sqlite::Result<Response> {
+    let canonical = match canonical_number(number) {
+        Some(x) => x,
+        None => return Ok(invalid_number(number)),
+    };
+    transaction(db, || {
+        if !touch(db, key)? {
+            return Ok(not_found(key));
+        }
+        let mut statement =
+            db.prepare("SELECT 1 FROM numbers WHERE user = :key AND is_primary = 1")?;
+        statement.bind_by_name(":key", key)?;
+        let has_primary = statement.next()? == sqlite::State::Row;
+        match insert_number(db, key, label, &canonical, number, !has_primary) {
+            Ok(()) => {}
+            Err(e) if e.code == Some(SQLITE_CONSTRAINT) => {
+                return Ok(Response::Fail {
+                    kind: ErrorKind::AlreadyExists,
+                    message: format!("User '{}' already has number {}", key, canonical),
+                })
+            }
+            Err(e) => return Err(e),
+        }
+        contact(db, key)
+    })
+}
+
+fn remove_number(db: &Connection, key: &str, number: &str) -> sqlite::Result<Response> {
+    let canonical = match canonical_number(number) {
+        Some(x) => x,
+        None => return Ok(invalid_number(number)),
+    };
+    transaction(db, || {
+        if !touch(db, key)? {
+            return Ok(not_found(key));
+        }
+        let mut statement =
+            db.prepare("DELETE FROM numbers WHERE user = :key AND number = :number")?;
+        statement.bind_by_name(":key", key)?;
+        statement.bind_by_name(":number", canonical.as_str())?;
+        statement.next()?;
+        if db.change_count() == 0 {
+            return Ok(number_not_found(key, &canonical));
+        }
+        let mut statement = db.prepare(
+            "UPDATE numbers SET is_primary = 1 \
+            WHERE rowid = (SELECT MIN(rowid) FROM numbers WHERE user = :key) \
+            AND NOT EXISTS (SELECT 1 FROM numbers WHERE user = :key AND is_primary = 1)",
+        )?;
+        statement.bind_by_name(":key", key)?;
+        statement.next()?;
+        contact(db, key)
+    })
+}
+
+fn set_primary(db: &Connection, key: &str, number: &str) -> sqlite::Result<Response> {
+    let canonical = match canonical_number(number) {
+        Some(x) => x,
+        None => return Ok(invalid_number(number)),
+    };
+    transaction(db, || {
+        if !touch(db, key)? {
+            return Ok(not_found(key));
+        }
+        let mut statement =
+            db.prepare("SELECT 1 FROM numbers WHERE user = :key AND number = :number")?;
+        statement.bind_by_name(":key", key)?;
+        statement.bind_by_name(":number", canonical.as_str())?;
+        if statement.next()? == sqlite::State::Done {
+            return Ok(number_not_found(key, &canonical));
+        }
+        let mut statement =
+            db.prepare("UPDATE numbers SET is_primary = (number = :number) WHERE user = :key")?;
+        statement.bind_by_name(":key", key)?;
+        statement.bind_by_name(":number", canonical.as_str())?;
+        statement.next()?;
+        contact(db, key)
+    })
+}
+
+fn edit_details(db: &Connection, key: &str, details: ContactDetails) -> sqlite::Result<Response> {
+    let details = details.normalized();
+    if let Err(message) = details.validate() {
+        return Ok(Response::Fail {
+            kind: ErrorKind::InvalidField,
+            message,
+        });
+    }
+    transaction(db, || {
+        let mut statement = db.prepare(
+            "UPDATE users SET email = :email, organization = :organization, notes = :notes, \
+            updated_at = strftime('%s', 'now') WHERE name = :key",
+        )?;
+        statement.bind_by_name(":key", key)?;
+        statement.bind_by_name(":email", details.email.as_deref())?;
+        statement.bind_by_name(":organization", details.organization.as_deref())?;
+        statement.bind_by_name(":notes", details.notes.as_deref())?;
+        statement.next()?;
+        if db.change_count() == 0 {
+            return Ok(not_found(key));
+        }
+        contact(db, key)
+    })
+}
+
+fn insert_number(
+    db: &Connection,
+    key: &str,
+    label: &str,
+    canonical: &str,
+    display: &str,
+    primary: bool,
+) -> sqlite::Result<()> {
+    let mut statement = db.prepare(
+        "INSERT INTO numbers (user, label, number, display_number, is_primary) \
+        VALUES (:key, :label, :number, :display, :primary)",
+    )?;
+    statement.bind_by_name(":key", key)?;
+    statement.bind_by_name(":label", label)?;
+    statement.bind_by_name(":number", canonical)?;
+    statement.bind_by_name(":display", display)?;
+    statement.bind_by_name(":primary", primary as i64)?;
+    statement.next()?;
+    Ok(())
+}
+
+/// Bumps the modification time of contact `key`, returns whether it exists.
+fn touch(db: &Connection, key: &str) -> sqlite::Result<bool> {
+    let mut statement =
+        db.prepare("UPDATE users SET updated_at = strftime('%s', 'now') WHERE name = :key")?;
+    statement.bind_by_name(":key", key)?;
+    statement.next()?;
+    Ok(db.change_count() > 0)
+}
+
+fn delete_user(db: &Connection, key: &str) -> sqlite::Result<Response> {
+    transaction(db, || {
+        let mut statement = db.prepare("DELETE FROM users WHERE name = :name")?;
+        statement.bind_by_name(":name", key)?;
+        statement.next()?;
+        if db.change_count() == 0 {
+            return Ok(not_found(key));
+        }
+        for table in ["numbers", "memberships"] {
+            let mut statement = db.prepare(format!("DELETE FROM {} WHERE user = :name", table))?;
+            statement.bind_by_name(":name", key)?;
+            statement.next()?;
+        }
+        Ok(Response::Success)
+    })
+}
+
+fn get_number(db: &Connection, key: &str) -> sqlite::Result<Response> {
+    let mut statement = db.prepare(
+        "SELECT number, display_number FROM numbers WHERE user = :key AND is_primary = 1",
+    )?;
+    statement.bind_by_name(":key", key)?;
+    match statement.next()? {
+        sqlite::State::Row => Ok(Response::Number {
+            number: statement.read::<String>(0)?,
+            display: statement.read::<String>(1)?,
+        }),
+        sqlite::State::Done => {
+            // Contacts lose their last number through `RemoveNumber`, or are
+            // created without one.
+            let mut statement = db.prepare("SELECT 1 FROM users WHERE name = :key")?;
+            statement.bind_by_name(":key", key)?;
+            match statement.next()? {
+                sqlite::State::Row => Ok(Response::Fail {
+                    kind: ErrorKind::NotFound,
+                    message: format!("User '{}' has no number", key),
+                }),
+                sqlite::State::Done => Ok(not_found(key)),
+            }
+        }
+    }
+}
+
+fn contact(db: &Connection, key: &str) -> sqlite::Result<Response> {
+    match read_contact(db, key)? {
+        Some(contact) => Ok(Response::Contact(contact)),
+        None => Ok(not_found(key)),
+    }
+}
+
+fn read_contact(db: &Connection, key: &str) -> sqlite::Result<Option<ContactInfo>> {
+    let mut statement = db.prepare(format!(
+        "{} WHERE users.name = :key {}",
+        SELECT_CONTACTS, ORDER_CONTACTS
+    ))?;
+    statement.bind_by_name(":key", key)?;
+    Ok(read_contacts(db, &mut statement)?.pop())
+}
+
+fn search(
+    db: &Connection,
+    query: &str,
+    fields: &[SearchField],
+    limit: u32,
+) -> sqlite::Result<Response> {
+    let field = |field| fields.is_empty() || fields.contains(&field);
+    let query = query.trim();
+    let digits: String = query.chars().filter(char::is_ascii_digit).collect();
+    // Numbers are stored in E.164 form, `+` followed by digits only.
+    let mut statement = db.prepare(
+        "SELECT users.name, MIN(CASE \
+            WHEN :names AND users.name LIKE :query || '%' ESCAPE '\\' THEN 0 \
+            WHEN :numbers AND SUBSTR(numbers.number, 2) LIKE :digits || '%' THEN 0 \
+            ELSE 1 END) AS rank \
+        FROM users LEFT JOIN numbers ON numbers.user = users.name \
+        WHERE (:names AND users.name LIKE '%' || :query || '%' ESCAPE '\\') \
+        OR (:numbers AND numbers.number LIKE '%' || :digits || '%') \
+        GROUP BY users.name ORDER BY rank, users.name LIMIT :limit",
+    )?;
+    statement.bind_by_name(":query", escape_like(query).as_str())?;
+    statement.bind_by_name(":digits", digits.as_str())?;
+    statement.bind_by_name(
+        ":names",
+        (field(SearchField::Name) && !query.is_empty()) as i64,
+    )?;
+    statement.bind_by_name(
+        ":numbers",
+        (field(SearchField::Number) && !digits.is_empty()) as i64,
+    )?;
+    statement.bind_by_name(":limit", limit as i64)?;
+    let mut names = vec![];
+    while let sqlite::State::Row = statement.next()? {
+        names.push(statement.read::<String>(0)?);
+    }
+    let mut contacts = vec![];
+    for name in names {
+        contacts.extend(read_contact(db, &name)?);
+    }
+    Ok(Response::SearchResults(contacts))
+}
+
+fn fuzzy_search(
+    db: &Connection,
+    index: &SearchIndex,
+    query: &str,
+    limit: u32,
+) -> sqlite::Result<Response> {
+    let mut contacts = vec![];
+    for (name, score) in index.search(query, limit as usize) {
+        if let Some(contact) = read_contact(db, &name)? {
+            contacts.push(ScoredContact { score, contact });
+        }
+    }
+    Ok(Response::ScoredResults(contacts))
+}
+
+/// Escapes the wildcards of `LIKE` patterns, `\` is the escape character.
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn list_users(
+    db: &Connection,
+    cursor: Option<String>,
+    limit: u32,
+    group: Option<String>,
+) -> sqlite::Result<Response> {
+    // One row more than requested tells whether there is a next page.
+    let mut statement = db.prepare(format!(
+        "{} WHERE users.name IN (SELECT name FROM users \
+        WHERE (:cursor IS NULL OR name > :cursor) \
+        AND (:group IS NULL OR name IN (SELECT memberships.user FROM memberships \
+            JOIN groups ON groups.rowid = memberships.group_id WHERE groups.name = :group)) \
+        ORDER BY name LIMIT :limit) {}",
+        SELECT_CONTACTS, ORDER_CONTACTS
+    ))?;
+    statement.bind_by_name(":cursor", cursor.as_deref())?;
+    statement.bind_by_name(":group", group.as_deref())?;
+    statement.bind_by_name(":limit", limit as i64 + 1)?;
+    let mut contacts = read_contacts(db, &mut statement)?;
+    let next_cursor = if contacts.len() > limit as usize {
+        contacts.truncate(limit as usize);
+        contacts.last().map(|x| x.name.clone())
+    } else {
+        None
+    };
+    Ok(Response::Page {
+        contacts,
+        next_cursor,
+    })
+}
+
+fn create_group(db: &Connection, name: &str) -> sqlite::Result<Response> {
+    let name = name.trim();
+    if let Err(message) = validate_group_name(name) {
+        return Ok(Response::Fail {
+            kind: ErrorKind::InvalidField,
+            message,
+        });
+    }
+    let mut statement = db.prepare("INSERT INTO groups (name) VALUES (:name)")?;
+    statement.bind_by_name(":name", name)?;
+    match statement.next() {
+        Ok(_) => Ok(Response::Success),
+        Err(e) if e.code == Some(SQLITE_CONSTRAINT) => Ok(group_exists(name)),
+        Err(e) => Err(e),
+    }
+}
+
+fn rename_group(db: &Connection, name: &str, new_name: &str) -> sqlite::Result<Response> {
+    let new_name = new_name.trim();
+    if let Err(message) = validate_group_name(new_name) {
+        return Ok(Response::Fail {
+            kind: ErrorKind::InvalidField,
+            message,
+        });
+    }
+    let mut statement = db.prepare("UPDATE groups SET name = :new_name WHERE name = :name")?;
+    statement.bind_by_name(":name", name)?;
+    statement.bind_by_name(":new_name", new_name)?;
+    match statement.next() {
+        Ok(_) if db.change_count() == 0 => Ok(group_not_found(name)),
+        Ok(_) => Ok(Response::Success),
+        Err(e) if e.code == Some(SQLITE_CONSTRAINT) => Ok(group_exists(new_name)),
+        Err(e) => Err(e),
+    }
+}
+
+fn list_groups(db: &Connection) -> sqlite::Result<Response> {
+    let mut statement = db.prepare("SELECT name FROM groups ORDER BY name")?;
+    let mut groups = vec![];
+    while let sqlite::State::Row = statement.next()? {
+        groups.push(statement.read::<String>(0)?);
+    }
+    Ok(Response::Groups(groups))
+}
+
+/// Adds contact `key` to `group`, or removes it from the group if `assign` is
+/// false.
+fn assign_group(db: &Connection, key: &str, group: &str, assign: bool) -> sqlite::Result<Response> {
+    transaction(db, || {
+        if !touch(db, key)? {
+            return Ok(not_found(key));
+        }
+        let mut statement = db.prepare("SELECT rowid FROM groups WHERE name = :group")?;
+        statement.bind_by_name(":group", group)?;
+        let group_id = match statement.next()? {
+            sqlite::State::Row => statement.read::<i64>(0)?,
+            sqlite::State::Done => return Ok(group_not_found(group)),
+        };
+        let mut statement = db.prepare(if assign {
+            "INSERT OR IGNORE INTO memberships (user, group_id) VALUES (:key, :group_id)"
+        } else {
+            "DELETE FROM memberships WHERE user = :key AND group_id = :group_id"
+        })?;
+        statement.bind_by_name(":key", key)?;
+        statement.bind_by_name(":group_id", group_id)?;
+        statement.next()?;
+        contact(db, key)
+    })
+}
+
+fn all_users(db: &Connection) -> sqlite::Result<Response> {
+    let mut statement = db.prepare(format!("{} {}", SELECT_CONTACTS, ORDER_CONTACTS))?;
+    Ok(Response::AllUsers(read_contacts(db, &mut statement)?))
+}
+
+/// Contacts joined with their numbers, read with [`read_contacts`].
+const SELECT_CONTACTS: &str = "SELECT users.name, numbers.label, numbers.number, \
+    numbers.display_number, numbers.is_primary, users.email, users.organization, \
+    users.notes FROM users \
+    LEFT JOIN numbers ON numbers.user = users.name";
+const ORDER_CONTACTS: &str = "ORDER BY users.name, numbers.is_primary DESC, numbers.rowid";
+
+/// Collects the rows of [`SELECT_CONTACTS`] into contacts, rows of a contact
+/// must be adjacent.
+fn read_contacts(
+    db: &Connection,
+    statement: &mut sqlite::Statement,
+) -> sqlite::Result<Vec<ContactInfo>> {
+    let mut contacts: Vec<ContactInfo> = vec![];
+    while let sqlite::State::Row = statement.next()? {
+        let name = statement.read::<String>(0)?;
+        if contacts.last().map(|x| &x.name) != Some(&name) {
+            contacts.push(ContactInfo {
+                name,
+                numbers: vec![],
+                details: ContactDetails {
+                    email: statement.read::<Option<String>>(5)?,
+                    organization: statement.read::<Option<String>>(6)?,
+                    notes: statement.read::<Option<String>>(7)?,
+                },
+                groups: vec![],
+            });
+        }
+        // Contacts without numbers produce a single row of NULLs.
+        if let Some(number) = statement.read::<Option<String>>(2)? {
+            if let Some(contact) = contacts.last_mut() {
+                contact.numbers.push(LabelledNumber {
+                    label: statement.read::<String>(1)?,
+                    number,
+                    display: statement.read::<String>(3)?,
+                    primary: statement.read::<i64>(4)? != 0,
+                });
+            }
+        }
+    }
+    let mut statement = db.prepare(
+        "SELECT groups.name FROM memberships JOIN groups ON groups.rowid = memberships.group_id \
+        WHERE memberships.user = :key ORDER BY groups.name",
+    )?;
+    for contact in &mut contacts {
+        statement.reset()?;
+        statement.bind_by_name(":key", contact.name.as_str())?;
+        while let sqlite::State::Row = statement.next()? {
+            contact.groups.push(statement.read::<String>(0)?);
+        }
+    }
+    Ok(contacts)
+}
+
+/// Runs `f` in a transaction, changes are rolled back if it fails.
+fn transaction(
+    db: &Connection,
+    f: impl FnOnce() -> sqlite::Result<Response>,
+) -> sqlite::Result<Response> {
+    db.execute("BEGIN")?;
+    // A failed `COMMIT`, e.g. while another connection holds a lock, leaves
+    // the transaction open, it has to be rolled back like any other failure.
+    // Failing to roll back is logged, the original error is the one to report.
+    match f().and_then(|response| db.execute("COMMIT").map(|_| response)) {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            if let Err(rollback) = db.execute("ROLLBACK") {
+                error!("Failed to roll back transaction: {}", rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// E.164 form of `number`, `None` if it is not a valid international number.
+pub fn canonical_number(number: &str) -> Option<String> {
+    phonenumber::parse(None, number)
+        .ok()
+        .filter(phonenumber::is_valid)
+        .map(|x| phonenumber::format(&x).mode(Mode::E164).to_string())
+}
+
+fn invalid_number(number: &str) -> Response {
+    Response::Fail {
+        kind: ErrorKind::InvalidNumber,
+        message: format!("'{}' is not a valid international phone number", number),
+    }
+}
+
+fn group_exists(name: &str) -> Response {
+    Response::Fail {
+        kind: ErrorKind::AlreadyExists,
+        message: format!("Group '{}' already exists", name),
+    }
+}
+
+fn group_not_found(name: &str) -> Response {
+    Response::Fail {
+        kind: ErrorKind::NotFound,
+        message: format!("Group '{}' does not exist", name),
+    }
+}
+
+fn number_not_found(key: &str, number: &str) -> Response {
+    Response::Fail {
+        kind: ErrorKind::NotFound,
+        message: format!("User '{}' has no number {}", key, number),
+    }
+}
+
+fn not_found(key: &str) -> Response {
+    Response::Fail {
+        kind: ErrorKind::NotFound,
+        message: format!("User '{}' does not exist", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_commit_is_rolled_back() {
+        let path = std::env::temp_dir().join(format!("phonebook-commit-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = sqlite::open(&path).unwrap();
+        crate::migrations::run(&db).unwrap();
+        let mut index = SearchIndex::new();
+        let add = |db: &Connection, index: &mut SearchIndex, key: &str| {
+            let instruction = Instruction::AddPhoneNumber {
+                key: key.to_owned(),
+                number: "+1 650 253 0000".to_owned(),
+            };
+            execute(db, index, instruction)
+        };
+
+        // Another connection reading the file keeps the commit from getting
+        // the lock it needs.
+        let reader = sqlite::open(&path).unwrap();
+        reader.execute("BEGIN; SELECT * FROM users;").unwrap();
+        match add(&db, &mut index, "Dan") {
+            Response::Fail { kind, .. } => assert_eq!(kind, ErrorKind::StorageError),
+            response => panic!("expected a storage error, got {:?}", response),
+        }
+        reader.execute("COMMIT").unwrap();
+
+        let get = |key: &str| {
+            execute(
+                &db,
+                &mut SearchIndex::new(),
+                Instruction::GetNumber {
+                    key: key.to_owned(),
+                },
+            )
+        };
+        assert!(matches!(
+            get("Dan"),
+            Response::Fail {
+                kind: ErrorKind::NotFound,
+                ..
+            }
+        ));
+        assert!(matches!(add(&db, &mut index, "Eve"), Response::Success));
+        assert!(matches!(get("Eve"), Response::Number { .. }));
+        drop(db);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn users_are_created_without_numbers() {
+        let db = sqlite::open(":memory:").unwrap();
+        crate::migrations::run(&db).unwrap();
+        let mut index = SearchIndex::new();
+        let create = |index: &mut SearchIndex| {
+            let instruction = Instruction::CreateUser {
+                key: "Dan".to_owned(),
+            };
+            execute(&db, index, instruction)
+        };
+
+        assert!(matches!(create(&mut index), Response::Success));
+        assert!(matches!(
+            create(&mut index),
+            Response::Fail {
+                kind: ErrorKind::AlreadyExists,
+                ..
+            }
+        ));
+        let instruction = Instruction::AddNumber {
+            key: "Dan".to_owned(),
+            label: "work".to_owned(),
+            number: "+1 650 253 0000".to_owned(),
+        };
+        match execute(&db, &mut index, instruction) {
+            Response::Contact(info) => {
+                assert_eq!(info.numbers.len(), 1);
+                assert!(info.numbers[0].primary);
+            }
+            response => panic!("expected the contact, got {:?}", response),
+        }
+    }
+
+    #[test]
+    fn contacts_without_numbers_have_no_number() {
+        let db = sqlite::open(":memory:").unwrap();
+        crate::migrations::run(&db).unwrap();
+        let mut index = SearchIndex::new();
+        let get = |index: &mut SearchIndex, key: &str| {
+            let instruction = Instruction::GetNumber {
+                key: key.to_owned(),
+            };
+            match execute(&db, index, instruction) {
+                Response::Fail { kind, message } => {
+                    assert_eq!(kind, ErrorKind::NotFound);
+                    message
+                }
+                response => panic!("expected a failure, got {:?}", response),
+            }
+        };
+        let instruction = Instruction::CreateUser {
+            key: "Dan".to_owned(),
+        };
+        assert!(matches!(
+            execute(&db, &mut index, instruction),
+            Response::Success
+        ));
+
+        assert_eq!(get(&mut index, "Dan"), "User 'Dan' has no number");
+        assert_eq!(get(&mut index, "Eve"), "User 'Eve' does not exist");
+    }
+}
@@ -1 +1,7 @@
-
+pub mod config;
+pub mod dedup;
+pub mod handler;
+pub mod migrations;
+pub mod ratelimit;
+pub mod search;
+pub mod session;
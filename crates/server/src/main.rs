@@ -1,109 +1,251 @@
-use common::serde_json;
+use common::codec::Codec;
+use common::fragment::{self, Fragment, MAX_DATAGRAM_SIZE};
+use common::Envelope;
+use common::ErrorKind;
 use common::Instruction;
+use common::RequestId;
 use common::Response;
+use log::{debug, error, info, warn};
+use server::config::{self, Config};
+use server::dedup::DedupCache;
+use server::handler;
+use server::migrations;
+use server::ratelimit::RateLimiter;
+use server::search::SearchIndex;
+use server::session::Sessions;
 use std::io::BufRead;
 use std::io::Write;
-use std::net::UdpSocket;
-fn main() -> Result<(), std::io::Error> {
-    let db = sqlite::open(":memory:").unwrap();
-    db.execute("CREATE TABLE users (name TEXT, number TEXT)")
-        .unwrap();
-    print!("Enter address where to bind socket to: ");
-    let mut addr = String::new();
-    std::io::stdout().flush()?;
-    std::io::stdin().lock().read_line(&mut addr)?;
-    let socket = UdpSocket::bind(addr.trim())?;
-    println!("- Socket bound to {}", addr.trim());
-    // we do not want to allocate 1KB slice on stack
-    let mut buf = vec![0u8; 1024];
-    loop {
-        let (bytes, source_addr) = socket.recv_from(&mut buf)?;
-
-        let ins = serde_json::from_slice::<Instruction>(&buf[..bytes])
-            .expect("Failed to deserialize server instruction");
-        match ins {
-            Instruction::AddPhoneNumber { key, number } => {
-                println!("- AddPhoneNumber: {} {}", key, number);
-                let statement = "INSERT INTO users VALUES (:key, :number)";
-                let mut statement = db.prepare(statement).unwrap();
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Instant;
 
-                statement.bind_by_name(":key", key.as_str()).unwrap();
-                statement.bind_by_name(":number", number.as_str()).unwrap();
+/// How long writes wait for other connections to the database file, e.g. a
+/// backup, to release their locks before failing.
+const BUSY_TIMEOUT_MS: usize = 5000;
 
-                match statement.next() {
-                    Ok(sqlite::State::Done) => {}
-                    Err(e) => {
-                        println!("Sqlite error: {}", e);
-                        socket.send_to(
-                            &serde_json::to_vec(&Response::Fail {
-                                message: format!("Sqlite failure on adding user entry: {}", e),
-                            })
-                            .unwrap(),
-                            source_addr,
-                        )?;
-                    }
-                    _ => unreachable!("Statement should be executed"),
-                }
+fn main() -> Result<(), std::io::Error> {
+    let config = match Config::load(std::env::args().skip(1)) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            println!("{}", config::USAGE);
+            return Ok(());
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", config::USAGE);
+            std::process::exit(1);
+        }
+    };
+    env_logger::Builder::new()
+        .filter_level(config.log_level)
+        .format_target(false)
+        .init();
+    let db = match sqlite::open(&config.db).and_then(|mut db| {
+        db.set_busy_timeout(BUSY_TIMEOUT_MS)?;
+        Ok(db)
+    }) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("Failed to open database '{}': {}", config.db, e);
+            std::process::exit(1);
+        }
+    };
+    info!("Using database {}", config.db);
+    match migrations::run(&db) {
+        Ok(version) if version < migrations::SCHEMA_VERSION => info!(
+            "Migrated database schema from version {} to {}",
+            version,
+            migrations::SCHEMA_VERSION
+        ),
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to migrate database '{}': {}", config.db, e);
+            std::process::exit(1);
+        }
+    }
+    let mut index = match SearchIndex::load(&db) {
+        Ok(index) => index,
+        Err(e) => {
+            error!("Failed to index contacts of '{}': {}", config.db, e);
+            std::process::exit(1);
+        }
+    };
+    info!("Indexed {} contacts for search", index.len());
+    // Loading the phone number metadata takes a while, do it now rather than
+    // while the first client waits for a response.
+    let _ = &*phonenumber::metadata::DATABASE;
+    let addr = match &config.bind {
+        Some(addr) => addr.clone(),
+        None => {
+            print!("Enter address where to bind socket to: ");
+            let mut addr = String::new();
+            std::io::stdout().flush()?;
+            std::io::stdin().lock().read_line(&mut addr)?;
+            if addr.trim().is_empty() {
+                error!("No address to bind to, pass --bind or set PHONEBOOK_BIND");
+                std::process::exit(1);
             }
-            Instruction::EditNumber { key, number } => {
-                println!("- Edit number: {} {}", key, number);
-                let statement = "UPDATE users SET number = :number WHERE name = :key";
-                let mut statement = db.prepare(statement).unwrap();
-
-                statement.bind_by_name(":key", key.as_str()).unwrap();
-                statement.bind_by_name(":number", number.as_str()).unwrap();
-                match statement.next() {
-                    Ok(sqlite::State::Done) => {}
-                    Err(e) => {
-                        println!("Sqlite error: {}", e);
-                        socket.send_to(
-                            &serde_json::to_vec(&Response::Fail {
-                                message: format!("Sqlite failure on adding user entry: {}", e),
-                            })
-                            .unwrap(),
-                            source_addr,
-                        )?;
-                    }
-                    _ => unreachable!("Statement should be executed"),
+            addr.trim().to_owned()
+        }
+    };
+    let socket = UdpSocket::bind(&addr)?;
+    info!("Socket bound to {}", addr);
+    if config.auth.token.is_some() {
+        info!("Clients must send an access token");
+    }
+    if !config.auth.allowed_addresses.is_empty() {
+        info!("Serving only {:?}", config.auth.allowed_addresses);
+    }
+    // we do not want to allocate 64KB slice on stack
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let limits = &config.limits;
+    let mut cache = DedupCache::new(
+        limits.max_sessions,
+        limits.dedup_responses_per_client,
+        limits.dedup_bytes,
+    );
+    let mut sessions = Sessions::new(limits.max_sessions, config.auth.token.clone());
+    let mut rate_limiter = RateLimiter::new(limits.requests_per_second);
+    loop {
+        let (bytes, source_addr) = match socket.recv_from(&mut buf) {
+            Ok(x) => x,
+            // Errors of a single datagram (e.g. ICMP port unreachable reported
+            // for an earlier reply) must not take the server down.
+            Err(e) => {
+                warn!("Failed to receive datagram: {}", e);
+                continue;
+            }
+        };
+        let codec = Codec::detect(&buf[..bytes]);
+        if !config.is_allowed(source_addr.ip()) {
+            warn!("Refusing {}: address not allowed", source_addr);
+            if let Some(id) = Codec::decode_id(&buf[..bytes]) {
+                let response = Response::Fail {
+                    kind: ErrorKind::Unauthorized,
+                    message: "This address is not allowed to use the server".to_owned(),
+                };
+                respond(&socket, source_addr, id, response, codec);
+            }
+            continue;
+        }
+        let Envelope { id, body: ins } = match Codec::decode::<Envelope<Instruction>>(&buf[..bytes])
+        {
+            Ok(x) => x,
+            Err(e) => {
+                // A valid envelope around an instruction we do not know most
+                // likely comes from a newer client, tell it instead of
+                // leaving it waiting.
+                if let Some(id) = Codec::decode_id(&buf[..bytes]) {
+                    warn!("Unknown instruction from {}: {}", source_addr, e);
+                    let response = Response::Fail {
+                        kind: ErrorKind::ProtocolVersionMismatch,
+                        message: format!("Unsupported instruction: {}", e),
+                    };
+                    respond(&socket, source_addr, id, response, codec);
+                } else {
+                    warn!(
+                        "Dropping malformed datagram ({} bytes) from {}: {}",
+                        bytes, source_addr, e
+                    );
                 }
+                continue;
             }
-            Instruction::DeleteUser { key } => {
-                println!("- Delete user {}", key);
-                let statement = "DELETE FROM users WHERE name = :name";
-                let mut statement = db.prepare(statement).unwrap();
-                statement.bind_by_name(":name", key.as_str()).unwrap();
-                match statement.next() {
-                    Ok(sqlite::State::Done) => {}
-                    Err(e) => {
-                        println!("Sqlite error: {}", e);
-                        socket.send_to(
-                            &serde_json::to_vec(&Response::Fail {
-                                message: format!("Failed to receive user '{}' number: {}", key, e),
-                            })
-                            .unwrap(),
-                            source_addr,
-                        )?;
+        };
+        if let Instruction::ResendFragments { request, indices } = &ins {
+            if let Some(bytes) = cache.get(source_addr, *request) {
+                debug!(
+                    "Resending {} fragments of request {} to {}",
+                    indices.len(),
+                    request,
+                    source_addr
+                );
+                for fragment in fragment::split(*request, bytes) {
+                    if indices.contains(&fragment.index) {
+                        send_fragment(&socket, source_addr, &fragment, Codec::detect(bytes));
                     }
-                    _ => unreachable!("Statement should be executed"),
                 }
+                continue;
             }
-            Instruction::GetAllUsers => {
-                let statement = "SELECT * FROM users";
-                let mut vec = vec![];
-                println!("- Fetching users...");
-                db.iterate(statement, |pairs| {
-                    let name = pairs[0].1.unwrap();
-                    let number = pairs[1].1.unwrap();
-                    println!("{} {}", name, number);
-                    vec.push((name.to_string(), number.to_string()));
-                    true
-                })
-                .unwrap();
-
-                let bytes = serde_json::to_vec(&Response::AllUsers(vec)).unwrap();
+        }
+        if let Some(bytes) = cache.get(source_addr, id) {
+            debug!("Replaying response to request {} of {}", id, source_addr);
+            send_response(&socket, source_addr, id, bytes);
+            continue;
+        }
+        // Refused requests are not cached, the retransmission may get through.
+        if !rate_limiter.allow(source_addr, Instant::now()) {
+            debug!("Rate limiting request {} of {}", id, source_addr);
+            let response = Response::Fail {
+                kind: ErrorKind::RateLimited,
+                message: "Too many requests, try again later".to_owned(),
+            };
+            respond(&socket, source_addr, id, response, codec);
+            continue;
+        }
+        let response = match ins {
+            Instruction::Hello {
+                protocol_version,
+                client_name,
+                codecs,
+                token,
+            } => sessions.hello(
+                source_addr,
+                protocol_version,
+                client_name,
+                &codecs,
+                token.as_deref(),
+            ),
+            _ if sessions.get(source_addr).is_none() => Response::Fail {
+                kind: ErrorKind::HandshakeRequired,
+                message: "Handshake required, send Hello first".to_owned(),
+            },
+            ins => handler::execute(&db, &mut index, ins),
+        };
+        let codec = sessions
+            .get(source_addr)
+            .map(|session| session.codec)
+            .unwrap_or(codec);
+        if let Some(bytes) = respond(&socket, source_addr, id, response, codec) {
+            cache.insert(source_addr, id, bytes);
+        }
+    }
+}
 
-                socket.send_to(&bytes, source_addr).unwrap();
-            }
+/// Encodes and sends `response`, returns the encoded response for caching.
+fn respond(
+    socket: &UdpSocket,
+    addr: SocketAddr,
+    id: RequestId,
+    response: Response,
+    codec: Codec,
+) -> Option<Vec<u8>> {
+    match codec.encode(&Envelope::new(id, response)) {
+        Ok(bytes) => {
+            send_response(socket, addr, id, &bytes);
+            Some(bytes)
+        }
+        Err(e) => {
+            error!("Failed to encode response to request {}: {}", id, e);
+            None
         }
     }
 }
+
+/// Sends an encoded response split into fragments, fragments are encoded with
+/// the same codec as the response.
+fn send_response(socket: &UdpSocket, addr: SocketAddr, id: RequestId, bytes: &[u8]) {
+    let codec = Codec::detect(bytes);
+    for fragment in fragment::split(id, bytes) {
+        send_fragment(socket, addr, &fragment, codec);
+    }
+}
+
+/// Failing to answer one client is logged, the client will retransmit.
+fn send_fragment(socket: &UdpSocket, addr: SocketAddr, fragment: &Fragment, codec: Codec) {
+    let sent = codec
+        .encode(fragment)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        .and_then(|bytes| socket.send_to(&bytes, addr));
+    if let Err(e) = sent {
+        warn!("Failed to send response to {}: {}", addr, e);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::handler::canonical_number;
+use log::warn;
+use sqlite::Connection;
+use std::fmt;
+
+/// Schema migration, either plain SQL or a function for changes SQL cannot
+/// express.
+enum Migration {
+    Sql(&'static str),
+    Rust(fn(&Connection) -> sqlite::Result<()>),
+}
+
+use Migration::{Rust, Sql};
+
+/// Ordered list of schema migrations. Migration at index `i` upgrades the
+/// database from version `i` to version `i + 1`, the version itself is kept in
+/// sqlite's `user_version` pragma.
+///
+/// Never edit or reorder existing entries, append new ones instead.
+const MIGRATIONS: &[Migration] = &[
+    // 1: initial schema. `IF NOT EXISTS` because databases created before
+    // migrations were introduced already have this table at version 0.
+    Sql("CREATE TABLE IF NOT EXISTS users (name TEXT, number TEXT);"),
+    // 2: contact names are unique. Older databases could hold duplicates, keep
+    // the most recently inserted row for each name.
+    Sql("DELETE FROM users WHERE rowid NOT IN (SELECT MAX(rowid) FROM users GROUP BY name);
+     CREATE UNIQUE INDEX users_name ON users (name);"),
+    // 3: created/updated timestamps, seconds since unix epoch.
+    Sql("ALTER TABLE users ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0;
+     ALTER TABLE users ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0;
+     UPDATE users SET created_at = strftime('%s', 'now'), updated_at = strftime('%s', 'now');"),
+    // 4: numbers are stored in E.164 form, the entered one is kept for display.
+    // Dropping the separators does not turn every number clients accepted into
+    // E.164, e.g. ones with letters or an extension, migration 8 fixes those.
+    Sql("ALTER TABLE users ADD COLUMN display_number TEXT NOT NULL DEFAULT '';
+     UPDATE users SET display_number = number;
+     UPDATE users SET number =
+        REPLACE(REPLACE(REPLACE(REPLACE(REPLACE(number, ' ', ''), '-', ''), '(', ''), ')', ''), '.', '');"),
+    // 5: contacts have any number of labelled numbers, one of them primary.
+    // The number columns move out of `users`, which is rebuilt without them.
+    Sql("CREATE TABLE numbers (
+        user TEXT NOT NULL,
+        label TEXT NOT NULL,
+        number TEXT NOT NULL,
+        display_number TEXT NOT NULL,
+        is_primary INTEGER NOT NULL DEFAULT 0,
+        UNIQUE (user, number)
+     );
+     INSERT INTO numbers (user, label, number, display_number, is_primary)
+        SELECT name, 'mobile', number, display_number, 1 FROM users
+        WHERE name IS NOT NULL AND number IS NOT NULL;
+     CREATE TABLE users_new (
+        name TEXT,
+        created_at INTEGER NOT NULL DEFAULT 0,
+        updated_at INTEGER NOT NULL DEFAULT 0
+     );
+     INSERT INTO users_new (name, created_at, updated_at)
+        SELECT name, created_at, updated_at FROM users;
+     DROP TABLE users;
+     ALTER TABLE users_new RENAME TO users;
+     CREATE UNIQUE INDEX users_name ON users (name);"),
+    // 6: optional contact details.
+    Sql("ALTER TABLE users ADD COLUMN email TEXT;
+     ALTER TABLE users ADD COLUMN organization TEXT;
+     ALTER TABLE users ADD COLUMN notes TEXT;"),
+    // 7: groups of contacts. Memberships refer to groups by rowid so renaming a
+    // group does not touch them.
+    Sql("CREATE TABLE groups (name TEXT NOT NULL);
+     CREATE UNIQUE INDEX groups_name ON groups (name);
+     CREATE TABLE memberships (
+        user TEXT NOT NULL,
+        group_id INTEGER NOT NULL,
+        UNIQUE (user, group_id)
+     );"),
+    // 8: numbers left out of E.164 form by migration 4, parsed again from the
+    // entered form.
+    Rust(canonicalize_numbers),
+];
+
+/// Schema version this binary was built for.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(sqlite::Error),
+    /// Database was written by a newer server, we must not touch it.
+    TooNew {
+        found: i64,
+        supported: i64,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sqlite(e) => write!(f, "sqlite error: {}", e),
+            Self::TooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the latest supported version {}",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<sqlite::Error> for MigrationError {
+    fn from(e: sqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+pub fn schema_version(db: &Connection) -> Result<i64, sqlite::Error> {
+    let mut statement = db.prepare("PRAGMA user_version")?;
+    statement.next()?;
+    statement.read::<i64>(0)
+}
+
+/// Brings the database up to [`SCHEMA_VERSION`], returns the version the
+/// database had before.
+pub fn run(db: &Connection) -> Result<i64, MigrationError> {
+    let found = schema_version(db)?;
+    if found > SCHEMA_VERSION {
+        return Err(MigrationError::TooNew {
+            found,
+            supported: SCHEMA_VERSION,
+        });
+    }
+    for (version, migration) in MIGRATIONS.iter().enumerate().skip(found as usize) {
+        let version = version as i64 + 1;
+        db.execute("BEGIN")?;
+        let applied = match migration {
+            Sql(sql) => db.execute(sql),
+            Rust(migrate) => migrate(db),
+        }
+        .and_then(|_| db.execute(format!("PRAGMA user_version = {}", version)));
+        match applied {
+            Ok(_) => db.execute("COMMIT")?,
+            Err(e) => {
+                db.execute("ROLLBACK")?;
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Rewrites every number that is not in E.164 form. Numbers that do not parse
+/// at all are kept as they are and logged, they can only be removed by hand.
+fn canonicalize_numbers(db: &Connection) -> sqlite::Result<()> {
+    let mut numbers = vec![];
+    let mut statement =
+        db.prepare("SELECT rowid, user, number, display_number, is_primary FROM numbers")?;
+    while let sqlite::State::Row = statement.next()? {
+        numbers.push((
+            statement.read::<i64>(0)?,
+            statement.read::<String>(1)?,
+            statement.read::<String>(2)?,
+            statement.read::<String>(3)?,
+            statement.read::<i64>(4)? != 0,
+        ));
+    }
+    for (rowid, user, number, display, primary) in numbers {
+        let canonical = match canonical_number(&display).or_else(|| canonical_number(&number)) {
+            Some(canonical) if canonical == number => continue,
+            Some(canonical) => canonical,
+            None => {
+                warn!(
+                    "Number '{}' of {} is not a valid phone number, keeping it as is",
+                    display, user
+                );
+                continue;
+            }
+        };
+        let mut statement =
+            db.prepare("SELECT rowid FROM numbers WHERE user = :user AND number = :number")?;
+        statement.bind_by_name(":user", user.as_str())?;
+        statement.bind_by_name(":number", canonical.as_str())?;
+        let duplicate = match statement.next()? {
+            sqlite::State::Row => Some(statement.read::<i64>(0)?),
+            sqlite::State::Done => None,
+        };
+        match duplicate {
+            // The contact has this number already, in E.164 form. Keep that
+            // one, primary if this one was.
+            Some(duplicate) => {
+                let mut statement = db.prepare("DELETE FROM numbers WHERE rowid = :rowid")?;
+                statement.bind_by_name(":rowid", rowid)?;
+                statement.next()?;
+                if primary {
+                    let mut statement =
+                        db.prepare("UPDATE numbers SET is_primary = 1 WHERE rowid = :rowid")?;
+                    statement.bind_by_name(":rowid", duplicate)?;
+                    statement.next()?;
+                }
+            }
+            None => {
+                let mut statement =
+                    db.prepare("UPDATE numbers SET number = :number WHERE rowid = :rowid")?;
+                statement.bind_by_name(":number", canonical.as_str())?;
+                statement.bind_by_name(":rowid", rowid)?;
+                statement.next()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbers(db: &Connection) -> Vec<(String, String, String, i64)> {
+        let mut statement = db
+            .prepare(
+                "SELECT user, number, display_number, is_primary FROM numbers ORDER BY user, rowid",
+            )
+            .unwrap();
+        let mut rows = vec![];
+        while let sqlite::State::Row = statement.next().unwrap() {
+            rows.push((
+                statement.read::<String>(0).unwrap(),
+                statement.read::<String>(1).unwrap(),
+                statement.read::<String>(2).unwrap(),
+                statement.read::<i64>(3).unwrap(),
+            ));
+        }
+        rows
+    }
+
+    #[test]
+    fn upgrades_version_0() {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(
+            "CREATE TABLE users (name TEXT, number TEXT);
+             INSERT INTO users VALUES ('Ann', '+1 650 253 0000');
+             INSERT INTO users VALUES ('Bob', '+1 650 253 0001');
+             INSERT INTO users VALUES ('Ann', '+1 650 253 0002');",
+        )
+        .unwrap();
+        assert_eq!(run(&db).unwrap(), 0);
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION);
+        // The most recently inserted of the duplicate names survives.
+        assert_eq!(
+            numbers(&db),
+            vec![
+                (
+                    "Ann".to_owned(),
+                    "+16502530002".to_owned(),
+                    "+1 650 253 0002".to_owned(),
+                    1
+                ),
+                (
+                    "Bob".to_owned(),
+                    "+16502530001".to_owned(),
+                    "+1 650 253 0001".to_owned(),
+                    1
+                ),
+            ]
+        );
+        let mut statement = db.prepare("SELECT name FROM users ORDER BY name").unwrap();
+        let mut users = vec![];
+        while let sqlite::State::Row = statement.next().unwrap() {
+            users.push(statement.read::<String>(0).unwrap());
+        }
+        assert_eq!(users, ["Ann", "Bob"]);
+        // Running again on an up to date database changes nothing.
+        assert_eq!(run(&db).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(format!("PRAGMA user_version = {}", SCHEMA_VERSION + 1))
+            .unwrap();
+        match run(&db) {
+            Err(MigrationError::TooNew { found, supported }) => {
+                assert_eq!(found, SCHEMA_VERSION + 1);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            result => panic!("expected TooNew, got {:?}", result),
+        }
+        assert_eq!(schema_version(&db).unwrap(), SCHEMA_VERSION + 1);
+    }
+
+    #[test]
+    fn numbers_are_canonicalized() {
+        let db = sqlite::open(":memory:").unwrap();
+        db.execute(
+            "CREATE TABLE users (name TEXT, number TEXT);
+             INSERT INTO users VALUES ('Ann', '+1/650/253/0000');
+             INSERT INTO users VALUES ('Bob', '+1 800 FLOWERS');
+             INSERT INTO users VALUES ('Cid', '+1 650 253 0001 ext. 12');
+             INSERT INTO users VALUES ('Dan', 'not a number');",
+        )
+        .unwrap();
+        run(&db).unwrap();
+        let row = |user: &str, number: &str, display: &str| {
+            (user.to_owned(), number.to_owned(), display.to_owned(), 1)
+        };
+        assert_eq!(
+            numbers(&db),
+            vec![
+                row("Ann", "+16502530000", "+1/650/253/0000"),
+                row("Bob", "+18003569377", "+1 800 FLOWERS"),
+                row("Cid", "+16502530001", "+1 650 253 0001 ext. 12"),
+                row("Dan", "notanumber", "not a number"),
+            ]
+        );
+    }
+
+    #[test]
+    fn canonical_duplicates_are_merged() {
+        let db = sqlite::open(":memory:").unwrap();
+        for migration in &MIGRATIONS[..7] {
+            match migration {
+                Sql(sql) => db.execute(sql).unwrap(),
+                Rust(migrate) => migrate(&db).unwrap(),
+            }
+        }
+        db.execute(
+            "PRAGMA user_version = 7;
+             INSERT INTO users (name) VALUES ('Ann');
+             INSERT INTO numbers VALUES ('Ann', 'mobile', '+1/650/253/0000', '+1/650/253/0000', 1);
+             INSERT INTO numbers VALUES ('Ann', 'work', '+16502530000', '+1 650 253 0000', 0);",
+        )
+        .unwrap();
+        run(&db).unwrap();
+        assert_eq!(
+            numbers(&db),
+            vec![(
+                "Ann".to_owned(),
+                "+16502530000".to_owned(),
+                "+1 650 253 0000".to_owned(),
+                1
+            )]
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Counts the requests of every client per second and refuses those above
+/// the limit.
+///
+/// Only clients seen during the current second are remembered, so the
+/// memory used is bounded by the traffic of one second.
+pub struct RateLimiter {
+    per_second: u32,
+    clients: HashMap<SocketAddr, (Instant, u32)>,
+    last_pruned: Instant,
+}
+
+impl RateLimiter {
+    /// A limit of 0 accepts every request.
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            per_second,
+            clients: HashMap::new(),
+            last_pruned: Instant::now(),
+        }
+    }
+
+    /// Records a request of `addr` received at `now`, returns whether it is
+    /// within the limit.
+    pub fn allow(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        if self.per_second == 0 {
+            return true;
+        }
+        if now.duration_since(self.last_pruned) >= WINDOW {
+            self.clients
+                .retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+            self.last_pruned = now;
+        }
+        let (start, count) = self.clients.entry(addr).or_insert((now, 0));
+        if now.duration_since(*start) >= WINDOW {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.per_second
+    }
+}
@@ -0,0 +1,218 @@
+use sqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Lowest score a contact needs to be returned by [`SearchIndex::search`].
+pub const MIN_SCORE: f32 = 0.5;
+
+/// In-memory index of contact names for fuzzy search.
+///
+/// Names are folded to lowercase ASCII-ish words without diacritics, so
+/// `Zoë` is found by `zoe`. Candidates are the names sharing a trigram with
+/// the query, they are ranked by how close their words are to the words of
+/// the query, which tolerates typos.
+#[derive(Default)]
+pub struct SearchIndex {
+    /// Folded words of every indexed name.
+    names: HashMap<String, Vec<String>>,
+    trigrams: HashMap<String, HashSet<String>>,
+}
+
+impl SearchIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Indexes every contact stored in the database.
+    pub fn load(db: &Connection) -> sqlite::Result<Self> {
+        let mut index = Self::new();
+        let mut statement = db.prepare("SELECT name FROM users WHERE name IS NOT NULL")?;
+        while let sqlite::State::Row = statement.next()? {
+            index.insert(&statement.read::<String>(0)?);
+        }
+        Ok(index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn insert(&mut self, name: &str) {
+        let words = words(name);
+        for word in &words {
+            for trigram in trigrams(word) {
+                self.trigrams
+                    .entry(trigram)
+                    .or_default()
+                    .insert(name.to_owned());
+            }
+        }
+        self.names.insert(name.to_owned(), words);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        if let Some(words) = self.names.remove(name) {
+            for trigram in words.iter().flat_map(|word| trigrams(word)) {
+                if let Some(names) = self.trigrams.get_mut(&trigram) {
+                    names.remove(name);
+                    if names.is_empty() {
+                        self.trigrams.remove(&trigram);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Up to `limit` names matching `query`, best matches first. Scores range
+    /// from [`MIN_SCORE`] to 1, which means every word of the query was found.
+    pub fn search(&self, query: &str, limit: usize) -> Vec<(String, f32)> {
+        let query = words(query);
+        if query.is_empty() {
+            return vec![];
+        }
+        let candidates: HashSet<&String> = query
+            .iter()
+            .flat_map(|word| trigrams(word))
+            .filter_map(|trigram| self.trigrams.get(&trigram))
+            .flatten()
+            .collect();
+        let mut hits: Vec<(String, f32)> = candidates
+            .into_iter()
+            .filter_map(|name| {
+                let words = self.names.get(name)?;
+                let score = query
+                    .iter()
+                    .map(|word| {
+                        words
+                            .iter()
+                            .map(|candidate| similarity(word, candidate))
+                            .fold(0.0, f32::max)
+                    })
+                    .sum::<f32>()
+                    / query.len() as f32;
+                Some((name.clone(), score)).filter(|_| score >= MIN_SCORE)
+            })
+            .collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hits.truncate(limit);
+        hits
+    }
+}
+
+/// Lowercase words of `text` with diacritics removed.
+fn words(text: &str) -> Vec<String> {
+    text.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Trigrams of `word` padded with two leading spaces, so words of any length
+/// have some and words sharing a prefix share the first ones.
+fn trigrams(word: &str) -> Vec<String> {
+    let chars: Vec<char> = "  ".chars().chain(word.chars()).collect();
+    chars
+        .windows(3)
+        .map(|window| window.iter().collect())
+        .collect()
+}
+
+/// How well query word `word` matches `candidate`, between 0 and 1. Prefixes
+/// of the candidate match almost as well as the whole word, since users type
+/// names from the start.
+fn similarity(word: &str, candidate: &str) -> f32 {
+    if word == candidate {
+        return 1.0;
+    }
+    if candidate.starts_with(word) {
+        return 0.9;
+    }
+    let distance = levenshtein(word, candidate);
+    let length = word.chars().count().max(candidate.chars().count());
+    1.0 - distance as f32 / length as f32
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + (a != *b) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(names: &[&str]) -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for name in names {
+            index.insert(name);
+        }
+        index
+    }
+
+    fn names(hits: Vec<(String, f32)>) -> Vec<String> {
+        hits.into_iter().map(|(name, _)| name).collect()
+    }
+
+    #[test]
+    fn words_are_folded() {
+        assert_eq!(words("Zoë O'Brien"), ["zoe", "o", "brien"]);
+        assert_eq!(words("ÅSA-Märta"), ["asa", "marta"]);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("", "abc"), 3);
+        assert_eq!(similarity("ann", "ann"), 1.0);
+        assert_eq!(similarity("ann", "annabel"), 0.9);
+    }
+
+    #[test]
+    fn diacritics_and_typos_are_tolerated() {
+        let index = index(&["Zoë Smith", "John Doe", "Mary"]);
+        assert_eq!(names(index.search("zoe", 10)), ["Zoë Smith"]);
+        assert_eq!(names(index.search("jhon", 10)), ["John Doe"]);
+    }
+
+    #[test]
+    fn exact_matches_rank_above_prefixes_and_typos() {
+        let index = index(&["Amn", "Annabel", "Ann"]);
+        let hits = index.search("ann", 10);
+        assert_eq!(names(hits.clone()), ["Ann", "Annabel", "Amn"]);
+        assert!(hits.windows(2).all(|x| x[0].1 > x[1].1));
+        assert_eq!(names(index.search("ann", 2)), ["Ann", "Annabel"]);
+    }
+
+    #[test]
+    fn removed_names_are_not_found() {
+        let mut index = index(&["Zoë", "Zoey"]);
+        index.remove("Zoë");
+        assert_eq!(index.len(), 1);
+        assert!(index.trigrams.values().all(|names| !names.contains("Zoë")));
+        assert_eq!(names(index.search("zoe", 10)), ["Zoey"]);
+        index.remove("Zoey");
+        assert!(index.is_empty());
+        assert!(index.trigrams.is_empty());
+    }
+
+    #[test]
+    fn empty_queries_find_nothing() {
+        let index = index(&["Ann"]);
+        assert!(index.search("", 10).is_empty());
+        assert!(index.search(" - ", 10).is_empty());
+    }
+}
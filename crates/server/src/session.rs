@@ -0,0 +1,118 @@
+use common::codec::Codec;
+use common::{ErrorKind, Response, PROTOCOL_VERSION};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Protocol versions this server can talk.
+pub const SUPPORTED_VERSIONS: &[u32] = &[PROTOCOL_VERSION];
+
+/// Optional protocol features, announced to clients in the handshake.
+pub const CAPABILITIES: &[&str] = &[
+    "lookup",
+    "paging",
+    "fragments",
+    "binary-codec",
+    "upsert",
+    "labelled-numbers",
+    "contact-details",
+    "groups",
+    "search",
+    "fuzzy-search",
+];
+
+pub struct Session {
+    pub client_name: String,
+    pub protocol_version: u32,
+    /// Codec responses to this client are encoded with.
+    pub codec: Codec,
+    established: u64,
+}
+
+/// Clients that completed the `Hello` handshake. Instructions of clients
+/// without a session are refused.
+pub struct Sessions {
+    max_sessions: usize,
+    /// Access token clients must present, if any.
+    token: Option<String>,
+    clock: u64,
+    sessions: HashMap<SocketAddr, Session>,
+}
+
+impl Sessions {
+    pub fn new(max_sessions: usize, token: Option<String>) -> Self {
+        Self {
+            max_sessions,
+            token,
+            clock: 0,
+            sessions: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, addr: SocketAddr) -> Option<&Session> {
+        self.sessions.get(&addr)
+    }
+
+    /// Answers the handshake of `addr`, starting a session if the client
+    /// speaks a version we support and presents the right access token.
+    pub fn hello(
+        &mut self,
+        addr: SocketAddr,
+        protocol_version: u32,
+        client_name: String,
+        codecs: &[Codec],
+        token: Option<&str>,
+    ) -> Response {
+        if !SUPPORTED_VERSIONS.contains(&protocol_version) {
+            info!(
+                "Refusing {} ({}): unsupported protocol version {}",
+                addr, client_name, protocol_version
+            );
+            return Response::Fail {
+                kind: ErrorKind::ProtocolVersionMismatch,
+                message: format!(
+                    "Protocol version {} is not supported, server supports {:?}",
+                    protocol_version, SUPPORTED_VERSIONS
+                ),
+            };
+        }
+        if self.token.is_some() && self.token.as_deref() != token {
+            warn!("Refusing {} ({}): invalid access token", addr, client_name);
+            return Response::Fail {
+                kind: ErrorKind::Unauthorized,
+                message: "Invalid access token".to_owned(),
+            };
+        }
+        // Every codec is supported, so the client's favourite one wins.
+        let codec = codecs.first().copied().unwrap_or_default();
+        info!(
+            "Session with {} ({}), protocol version {}, {:?} codec",
+            addr, client_name, protocol_version, codec
+        );
+        self.clock += 1;
+        if !self.sessions.contains_key(&addr) && self.sessions.len() >= self.max_sessions {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| session.established)
+                .map(|(addr, _)| *addr);
+            if let Some(oldest) = oldest {
+                self.sessions.remove(&oldest);
+            }
+        }
+        self.sessions.insert(
+            addr,
+            Session {
+                client_name,
+                protocol_version,
+                codec,
+                established: self.clock,
+            },
+        );
+        Response::Hello {
+            versions: SUPPORTED_VERSIONS.to_vec(),
+            capabilities: CAPABILITIES.iter().map(|x| x.to_string()).collect(),
+            codec,
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use client_core::is_valid_number;
+use client_core::session::Session;
+use common::codec::Codec;
+use common::fragment::{Fragment, MAX_DATAGRAM_SIZE};
+use common::retry::RetryPolicy;
+use common::ContactInfo;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::widgets::TableState;
+use ratatui::DefaultTerminal;
+use std::io;
+use std::time::{Duration, Instant};
+
+mod ui;
+
+/// Name the client introduces itself with in the handshake.
+const CLIENT_NAME: &str = concat!("tui client ", env!("CARGO_PKG_VERSION"));
+
+/// How long to wait for a key press before looking for responses.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Rows skipped by page up and page down.
+const PAGE_JUMP: usize = 10;
+
+// There is a single `App` for the whole run of the client, so the size
+// difference between the variants costs nothing.
+#[allow(clippy::large_enum_variant)]
+pub enum App {
+    Connect(ConnectForm),
+    Loaded(State),
+}
+
+pub struct ConnectForm {
+    /// Server address, client address and access token, in the order they
+    /// are shown.
+    fields: [String; 3],
+    focus: usize,
+    err: String,
+}
+
+const CONNECT_FIELDS: [&str; 3] = [
+    "Server address",
+    "Client address",
+    "Access token (optional)",
+];
+
+/// What key presses on the contact screen go to.
+pub enum Mode {
+    Browse,
+    Search,
+    Add {
+        name: String,
+        number: String,
+        /// Whether the number rather than the name is edited.
+        number_focused: bool,
+    },
+    Edit {
+        name: String,
+        number: String,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+pub struct State {
+    session: Session,
+    buf: Vec<u8>,
+    table: TableState,
+    mode: Mode,
+    /// Why the entered contact was not sent, shown instead of the key help
+    /// until the next key press.
+    hint: String,
+}
+
+impl State {
+    /// Contacts shown in the table, the search results while searching.
+    fn shown(&self) -> Vec<&ContactInfo> {
+        if self.session.search().is_empty() {
+            self.session.contacts().iter().map(|x| &x.info).collect()
+        } else {
+            self.session.search_results().iter().collect()
+        }
+    }
+
+    fn selected(&self) -> Option<&ContactInfo> {
+        self.table
+            .selected()
+            .and_then(|i| self.shown().get(i).copied())
+    }
+
+    /// Handles the responses that arrived and the requests that timed out.
+    fn tick(&mut self, now: Instant) {
+        let socket = self.session.socket();
+        // Errors reported for earlier datagrams, e.g. while the server is
+        // down, show up as timeouts of their requests.
+        while let Ok(bytes) = socket.recv(&mut self.buf) {
+            if let Ok(fragment) = Codec::decode::<Fragment>(&self.buf[..bytes]) {
+                self.session.receive(fragment);
+            }
+        }
+        self.session.tick(now);
+        self.clamp_selection();
+    }
+
+    /// Keeps the selection on a row as rows come and go.
+    fn clamp_selection(&mut self) {
+        let last = self.shown().len().checked_sub(1);
+        let selected = match self.table.selected() {
+            None => last.map(|_| 0),
+            Some(selected) => last.map(|last| selected.min(last)),
+        };
+        self.table.select(selected);
+    }
+
+    /// Moves the selection by `offset` rows, loading the next page when the
+    /// end of the contact list is reached.
+    fn scroll(&mut self, offset: isize) {
+        let len = self.shown().len();
+        if len == 0 {
+            return;
+        }
+        let selected = self.table.selected().unwrap_or(0);
+        let selected = selected.saturating_add_signed(offset).min(len - 1);
+        self.table.select(Some(selected));
+        if selected == len - 1 && self.session.search().is_empty() {
+            self.session.load_more();
+        }
+    }
+
+    /// Handles a key press, returns `false` once the user wants to quit.
+    fn update(&mut self, key: KeyEvent) -> bool {
+        self.hint.clear();
+        match &mut self.mode {
+            Mode::Browse => match key.code {
+                KeyCode::Char('q') => return false,
+                KeyCode::Esc if self.session.search().is_empty() => return false,
+                KeyCode::Esc => self.clear_search(),
+                KeyCode::Up | KeyCode::Char('k') => self.scroll(-1),
+                KeyCode::Down | KeyCode::Char('j') => self.scroll(1),
+                KeyCode::PageUp => self.scroll(-(PAGE_JUMP as isize)),
+                KeyCode::PageDown => self.scroll(PAGE_JUMP as isize),
+                KeyCode::Home => self.scroll(isize::MIN),
+                KeyCode::End => self.scroll(isize::MAX),
+                KeyCode::Char('/') => self.mode = Mode::Search,
+                KeyCode::Char('r') => self.session.fetch_page(true),
+                KeyCode::Char('a') => {
+                    self.mode = Mode::Add {
+                        name: String::new(),
+                        number: String::new(),
+                        number_focused: false,
+                    }
+                }
+                KeyCode::Char('e') | KeyCode::Enter => {
+                    if let Some(contact) = self.selected() {
+                        self.mode = Mode::Edit {
+                            name: contact.name.clone(),
+                            number: contact
+                                .primary()
+                                .map(|x| x.display.clone())
+                                .unwrap_or_default(),
+                        };
+                    }
+                }
+                KeyCode::Char('d') | KeyCode::Delete => {
+                    if let Some(contact) = self.selected() {
+                        self.mode = Mode::Delete {
+                            name: contact.name.clone(),
+                        };
+                    }
+                }
+                _ => {}
+            },
+            Mode::Search => match key.code {
+                KeyCode::Esc => {
+                    self.clear_search();
+                    self.mode = Mode::Browse;
+                }
+                KeyCode::Enter | KeyCode::Down => {
+                    self.mode = Mode::Browse;
+                    self.table.select(Some(0));
+                }
+                KeyCode::Char(c) => {
+                    let mut query = self.session.search().to_owned();
+                    query.push(c);
+                    self.session.set_search(query, Instant::now());
+                }
+                KeyCode::Backspace => {
+                    let mut query = self.session.search().to_owned();
+                    query.pop();
+                    self.session.set_search(query, Instant::now());
+                }
+                _ => {}
+            },
+            Mode::Add {
+                name,
+                number,
+                number_focused,
+            } => match key.code {
+                KeyCode::Esc => self.mode = Mode::Browse,
+                KeyCode::Tab | KeyCode::BackTab | KeyCode::Up | KeyCode::Down => {
+                    *number_focused = !*number_focused
+                }
+                KeyCode::Char(c) if *number_focused => number.push(c),
+                KeyCode::Char(c) => name.push(c),
+                KeyCode::Backspace if *number_focused => {
+                    number.pop();
+                }
+                KeyCode::Backspace => {
+                    name.pop();
+                }
+                KeyCode::Enter if name.trim().is_empty() => {
+                    self.hint = "Enter the name of the contact".to_owned()
+                }
+                KeyCode::Enter if !is_valid_number(number) => {
+                    self.hint = invalid_number();
+                    *number_focused = true;
+                }
+                KeyCode::Enter => {
+                    let name = name.trim().to_owned();
+                    let number = std::mem::take(number);
+                    self.mode = Mode::Browse;
+                    self.session.add(name, number);
+                }
+                _ => {}
+            },
+            Mode::Edit { name, number } => match key.code {
+                KeyCode::Esc => self.mode = Mode::Browse,
+                KeyCode::Char(c) => number.push(c),
+                KeyCode::Backspace => {
+                    number.pop();
+                }
+                KeyCode::Enter if !is_valid_number(number) => self.hint = invalid_number(),
+                KeyCode::Enter => {
+                    let name = std::mem::take(name);
+                    let number = std::mem::take(number);
+                    self.mode = Mode::Browse;
+                    self.session.edit_number(name, number);
+                }
+                _ => {}
+            },
+            Mode::Delete { name } => {
+                if let KeyCode::Char('y') = key.code {
+                    self.session.delete(std::mem::take(name));
+                }
+                self.mode = Mode::Browse;
+            }
+        }
+        true
+    }
+
+    fn clear_search(&mut self) {
+        self.session.set_search(String::new(), Instant::now());
+        self.clamp_selection();
+    }
+}
+
+fn invalid_number() -> String {
+    "Enter an international number, e.g. +1 650 253 0000".to_owned()
+}
+
+impl App {
+    fn new() -> Self {
+        Self::Connect(ConnectForm {
+            fields: [String::new(), "0.0.0.0:0".to_owned(), String::new()],
+            focus: 0,
+            err: String::new(),
+        })
+    }
+
+    /// Handles a key press, returns `false` once the user wants to quit.
+    fn update(&mut self, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return false;
+        }
+        let form = match self {
+            Self::Loaded(state) => return state.update(key),
+            Self::Connect(form) => form,
+        };
+        match key.code {
+            KeyCode::Esc => return false,
+            KeyCode::Tab | KeyCode::Down => form.focus = (form.focus + 1) % CONNECT_FIELDS.len(),
+            KeyCode::BackTab | KeyCode::Up => {
+                form.focus = (form.focus + CONNECT_FIELDS.len() - 1) % CONNECT_FIELDS.len()
+            }
+            KeyCode::Char(c) => {
+                form.fields[form.focus].push(c);
+                form.err.clear();
+            }
+            KeyCode::Backspace => {
+                form.fields[form.focus].pop();
+                form.err.clear();
+            }
+            KeyCode::Enter => match connect(form) {
+                Ok(state) => *self = Self::Loaded(state),
+                Err(e) => form.err = e,
+            },
+            _ => {}
+        }
+        true
+    }
+}
+
+/// Connects to the server entered in `form` and starts the handshake.
+fn connect(form: &ConnectForm) -> Result<State, String> {
+    let [server, bind, token] = &form.fields;
+    let token = Some(token.clone()).filter(|token| !token.is_empty());
+    let policy = RetryPolicy::default();
+    let session = Session::connect(bind.trim(), server.trim(), policy, CLIENT_NAME, token)
+        .map_err(|e| format!("Failed to connect `{}` to `{}`: {}", bind, server, e))?;
+    session
+        .socket()
+        .set_nonblocking(true)
+        .map_err(|e| format!("Failed to configure socket: {}", e))?;
+    Ok(State {
+        session,
+        buf: vec![0u8; MAX_DATAGRAM_SIZE],
+        table: TableState::default(),
+        mode: Mode::Browse,
+        hint: String::new(),
+    })
+}
+
+fn run(terminal: &mut DefaultTerminal) -> io::Result<()> {
+    let mut app = App::new();
+    loop {
+        terminal.draw(|frame| ui::draw(frame, &mut app))?;
+        if event::poll(POLL_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press && !app.update(key) {
+                    return Ok(());
+                }
+            }
+        }
+        if let App::Loaded(state) = &mut app {
+            state.tick(Instant::now());
+        }
+    }
+}
+
+fn main() -> io::Result<()> {
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal);
+    ratatui::restore();
+    result
+}
@@ -0,0 +1,204 @@
+use crate::{App, ConnectForm, Mode, State, CONNECT_FIELDS};
+use client_core::session::{Handshake, Status};
+use common::ContactInfo;
+use ratatui::layout::{Constraint, Flex, Layout, Position, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table};
+use ratatui::Frame;
+
+pub fn draw(frame: &mut Frame, app: &mut App) {
+    match app {
+        App::Connect(form) => draw_connect(frame, form),
+        App::Loaded(state) => draw_contacts(frame, state),
+    }
+}
+
+fn draw_connect(frame: &mut Frame, form: &ConnectForm) {
+    let [area] = Layout::horizontal([Constraint::Length(60)])
+        .flex(Flex::Center)
+        .areas(frame.area());
+    let [title, fields, help, err] = Layout::vertical([
+        Constraint::Length(2),
+        Constraint::Length(3 * CONNECT_FIELDS.len() as u16),
+        Constraint::Length(1),
+        Constraint::Min(0),
+    ])
+    .areas(area);
+    frame.render_widget(Line::from("Phone numbers".bold()).centered(), title);
+    let rows = Layout::vertical([Constraint::Length(3); CONNECT_FIELDS.len()]).split(fields);
+    for (i, (label, value)) in CONNECT_FIELDS.iter().zip(&form.fields).enumerate() {
+        // The token is a secret, do not show it on screen.
+        let shown = if i == 2 {
+            "*".repeat(value.chars().count())
+        } else {
+            value.clone()
+        };
+        input(frame, rows[i], label, &shown, i == form.focus);
+    }
+    frame.render_widget(
+        Line::from("Tab: next field  Enter: connect  Esc: quit").dark_gray(),
+        help,
+    );
+    if !form.err.is_empty() {
+        frame.render_widget(
+            Paragraph::new(format!("Error: {}", form.err))
+                .red()
+                .wrap(Default::default()),
+            err,
+        );
+    }
+}
+
+fn draw_contacts(frame: &mut Frame, state: &mut State) {
+    let session = &state.session;
+    let [search, err, table, bottom, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(if session.error().is_empty() { 0 } else { 1 }),
+        Constraint::Min(3),
+        Constraint::Length(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let title = if session.search_fuzzy() {
+        "Search (/) - no exact matches, showing similar names"
+    } else {
+        "Search (/)"
+    };
+    input(
+        frame,
+        search,
+        title,
+        session.search(),
+        matches!(state.mode, Mode::Search),
+    );
+    frame.render_widget(Line::from(format!("Error: {}", session.error())).red(), err);
+
+    let empty = match session.handshake() {
+        Handshake::Pending => "Connecting...".to_owned(),
+        Handshake::Refused(reason) => format!("Server refused the connection: {}", reason),
+        Handshake::Done if session.loading_page() => "Loading...".to_owned(),
+        Handshake::Done if session.search().is_empty() => "No contacts yet".to_owned(),
+        Handshake::Done => "No contacts found".to_owned(),
+    };
+    let (title, rows): (String, Vec<Row>) = if !session.search().is_empty() {
+        let results = session.search_results();
+        (
+            format!("Search results ({})", results.len()),
+            results.iter().map(contact_row).collect(),
+        )
+    } else {
+        let contacts = session.contacts();
+        let title = if session.has_more() {
+            format!("Contacts ({}, more below)", contacts.len())
+        } else {
+            format!("Contacts ({})", contacts.len())
+        };
+        // Changes the server has not confirmed, or refused, stand out.
+        let rows = contacts
+            .iter()
+            .map(|contact| {
+                let row = contact_row(&contact.info);
+                match contact.status {
+                    Status::Confirmed => row,
+                    Status::Pending => row.dark_gray(),
+                    Status::Failed(_) => row.red(),
+                }
+            })
+            .collect();
+        (title, rows)
+    };
+    let block = Block::bordered().title(title);
+    if rows.is_empty() {
+        frame.render_widget(Paragraph::new(empty).dark_gray().block(block), table);
+    } else {
+        let widths = [
+            Constraint::Fill(2),
+            Constraint::Length(20),
+            Constraint::Fill(3),
+            Constraint::Fill(2),
+        ];
+        let contacts = Table::new(rows, widths)
+            .header(Row::new(["Name", "Number", "Other numbers", "Groups"]).bold())
+            .row_highlight_style(Style::new().reversed())
+            .block(block);
+        frame.render_stateful_widget(contacts, table, &mut state.table);
+    }
+
+    match &state.mode {
+        Mode::Add {
+            name,
+            number,
+            number_focused,
+        } => {
+            let [name_area, number_area] =
+                Layout::horizontal([Constraint::Fill(1); 2]).areas(bottom);
+            input(frame, name_area, "Name", name, !number_focused);
+            input(frame, number_area, "Number", number, *number_focused);
+        }
+        Mode::Edit { name, number } => {
+            input(
+                frame,
+                bottom,
+                &format!("New number of {}", name),
+                number,
+                true,
+            );
+        }
+        Mode::Delete { name } => frame.render_widget(
+            Paragraph::new(format!("Delete {}? (y/n)", name))
+                .yellow()
+                .block(Block::bordered()),
+            bottom,
+        ),
+        Mode::Browse | Mode::Search => frame.render_widget(
+            Paragraph::new(state.session.status()).block(Block::bordered()),
+            bottom,
+        ),
+    }
+
+    let keys = match state.mode {
+        Mode::Browse => "a: add  e: edit  d: delete  /: search  r: reload  q: quit",
+        Mode::Search => "Enter: go to results  Esc: clear search",
+        Mode::Add { .. } => "Tab: next field  Enter: save  Esc: cancel",
+        Mode::Edit { .. } => "Enter: save  Esc: cancel",
+        Mode::Delete { .. } => "y: delete  any other key: cancel",
+    };
+    if state.hint.is_empty() {
+        frame.render_widget(Line::from(keys).dark_gray(), help);
+    } else {
+        frame.render_widget(Line::from(state.hint.as_str()).yellow(), help);
+    }
+}
+
+fn contact_row(contact: &ContactInfo) -> Row<'static> {
+    let others: Vec<String> = contact
+        .numbers
+        .iter()
+        .filter(|x| !x.primary)
+        .map(|x| format!("{}: {}", x.label, x.display))
+        .collect();
+    Row::new([
+        contact.name.clone(),
+        contact
+            .primary()
+            .map(|x| x.display.clone())
+            .unwrap_or_default(),
+        others.join(", "),
+        contact.groups.join(", "),
+    ])
+}
+
+/// Single line text input, the cursor is placed at its end while `focused`.
+fn input(frame: &mut Frame, area: Rect, title: &str, value: &str, focused: bool) {
+    let block = Block::bordered().title(title.to_owned());
+    let block = if focused { block.yellow() } else { block };
+    frame.render_widget(Paragraph::new(value.to_owned()).white().block(block), area);
+    if focused {
+        frame.set_cursor_position(Position::new(
+            area.x + 1 + value.chars().count() as u16,
+            area.y + 1,
+        ));
+    }
+}